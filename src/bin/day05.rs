@@ -1,6 +1,10 @@
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::env::args;
 use std::fs::read_to_string;
+use std::time::Instant;
 
 type Edges = Vec<(usize, usize)>;
 type Update = Vec<usize>;
@@ -32,22 +36,182 @@ impl Topo {
     fn remove_page(&mut self, page: usize) {
         let befores = self.after.get(&page).unwrap();
         for b in befores {
-            if let Some(pages) = self.before.get_mut(&b) {
+            if let Some(pages) = self.before.get_mut(b) {
                 pages.remove(&page);
             }
         }
     }
 
-    fn ordered(&mut self) -> Update {
+    // None if the rules go round in a cycle
+    fn ordered(&mut self) -> Option<Update> {
         let mut ret = Update::new();
 
         while !self.before.is_empty() {
-            let (&page, _) = self.before.iter().find(|(_, v)| v.is_empty()).unwrap();
+            let (&page, _) = self.before.iter().find(|(_, v)| v.is_empty())?;
             ret.push(page);
             self.before.remove(&page);
             self.remove_page(page);
         }
-        ret
+        Some(ret)
+    }
+}
+
+// Bitset adjacency over page IDs, built once for all updates
+struct RuleIndex {
+    words: usize,
+    before: Vec<u64>,
+}
+
+impl RuleIndex {
+    fn new(edges: &Edges) -> Self {
+        let pages = edges.iter().map(|&(b, a)| b.max(a) + 1).max().unwrap_or(0);
+        let words = pages.div_ceil(64);
+        let mut before = vec![0; pages * words];
+        for &(b, a) in edges {
+            before[b * words + a / 64] |= 1 << (a % 64);
+        }
+        Self { words, before }
+    }
+
+    fn must_precede(&self, b: usize, a: usize) -> bool {
+        let ix = b * self.words + a / 64;
+        ix < self.before.len() && a / 64 < self.words && self.before[ix] & (1 << (a % 64)) != 0
+    }
+
+    // A comparator sort is only valid if every pair of pages is ordered by exactly one rule,
+    // and the rules don't go round in a cycle. Then the pages each page must precede number
+    // 0, 1, ..., n-1, since the last page precedes none of them and the first all the others.
+    fn is_total(&self, update: &Update) -> bool {
+        let pairwise = update
+            .iter()
+            .tuple_combinations()
+            .all(|(&x, &y)| self.must_precede(x, y) != self.must_precede(y, x));
+        let mut counts = update
+            .iter()
+            .map(|&x| update.iter().filter(|&&y| self.must_precede(x, y)).count())
+            .collect_vec();
+        counts.sort_unstable();
+        pairwise && counts.iter().enumerate().all(|(i, c)| i == *c)
+    }
+
+    fn ordered(&self, update: &Update) -> Option<Update> {
+        if !self.is_total(update) {
+            return None;
+        }
+        let mut ret = update.clone();
+        ret.sort_by(|&x, &y| {
+            if x == y {
+                Ordering::Equal
+            } else if self.must_precede(x, y) {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        });
+        Some(ret)
+    }
+}
+
+#[derive(Debug)]
+struct Violation {
+    before: usize,
+    after: usize,
+    before_pos: usize,
+    after_pos: usize,
+}
+
+// Moves apply in order, with positions as they are after the earlier ones
+#[derive(Debug)]
+struct Move {
+    page: usize,
+    from: usize,
+    // The page to put it straight after, or None for the front
+    after: Option<usize>,
+}
+
+fn violations(edges: &Edges, update: &Update) -> Vec<Violation> {
+    let pos: HashMap<usize, usize> = update.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+    let mut ret = Vec::new();
+    for &(before, after) in edges {
+        if let (Some(&before_pos), Some(&after_pos)) = (pos.get(&before), pos.get(&after)) {
+            if before_pos > after_pos {
+                ret.push(Violation {
+                    before,
+                    after,
+                    before_pos,
+                    after_pos,
+                });
+            }
+        }
+    }
+    ret.sort_by_key(|v| (v.after_pos, v.before_pos));
+    ret
+}
+
+// Pages that stay put form the longest subsequence of the update that's already in order, so
+// moving everything else is the fewest relocations. Only the fewest when the rules totally order
+// the update, since otherwise other orders might need fewer. Moving pages in their final order,
+// each straight after the page before it there, leaves everything in place.
+fn minimal_moves(update: &Update, ordered: &Update) -> Vec<Move> {
+    let rank: HashMap<usize, usize> = ordered.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+    let ranks: Vec<usize> = update.iter().map(|p| rank[p]).collect();
+
+    // Longest increasing subsequence of ranks, with predecessor links to recover it
+    let mut tails: Vec<usize> = Vec::new();
+    let mut prev = vec![None; ranks.len()];
+    for (i, r) in ranks.iter().enumerate() {
+        let at = tails.partition_point(|&t| ranks[t] < *r);
+        if at > 0 {
+            prev[i] = Some(tails[at - 1]);
+        }
+        if at == tails.len() {
+            tails.push(i);
+        } else {
+            tails[at] = i;
+        }
+    }
+    let mut keep = vec![false; ranks.len()];
+    let mut cur = tails.last().copied();
+    while let Some(i) = cur {
+        keep[i] = true;
+        cur = prev[i];
+    }
+
+    let mut current = update.clone();
+    let mut moves = Vec::new();
+    let moving = update
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !keep[*i])
+        .map(|(_, page)| *page)
+        .sorted_by_key(|page| rank[page]);
+    for page in moving {
+        let from = current.iter().position(|p| *p == page).unwrap();
+        current.remove(from);
+        let after = rank[&page].checked_sub(1).map(|r| ordered[r]);
+        let to = after.map_or(0, |a| current.iter().position(|p| *p == a).unwrap() + 1);
+        current.insert(to, page);
+        moves.push(Move { page, from, after });
+    }
+    moves
+}
+
+fn explain(edges: &Edges, update: &Update, ordered: &Update, exact: bool) {
+    println!("Update {:?}", update);
+    for v in violations(edges, update) {
+        println!(
+            "  violates {}|{}: {} at {}, {} at {}",
+            v.before, v.after, v.before, v.before_pos, v.after, v.after_pos
+        );
+    }
+    if !exact {
+        println!("  rules don't order every pair, so these may not be the fewest moves");
+    }
+    for m in minimal_moves(update, ordered) {
+        match m.after {
+            Some(a) => println!("  move {} from {} to after {}", m.page, m.from, a),
+            None => println!("  move {} from {} to the front", m.page, m.from),
+        }
     }
 }
 
@@ -72,22 +236,76 @@ fn parse(fname: &str) -> (Edges, Vec<Update>) {
     (edges, updates)
 }
 
-fn main() {
-    let fname = args().nth(1).unwrap();
-    let (edges, updates) = parse(&fname);
+fn order_topo(edges: &Edges, update: &Update) -> Option<Update> {
+    Topo::new(edges, update).ordered()
+}
 
+// Falls back to the topological sort when the rules leave some pairs unordered
+fn order_indexed(index: &RuleIndex, edges: &Edges, update: &Update) -> Option<Update> {
+    index
+        .ordered(update)
+        .or_else(|| order_topo(edges, update))
+}
+
+fn solve(
+    updates: &[Update],
+    mut order: impl FnMut(&Update) -> Option<Update>,
+) -> Result<(usize, usize)> {
     let mut part1 = 0;
     let mut part2 = 0;
     for update in updates {
-        let mut topo = Topo::new(&edges, &update);
-        let ordered = topo.ordered();
+        let ordered = order(update).ok_or_else(|| anyhow!("rules form a cycle in {:?}", update))?;
         let mid = ordered.get(ordered.len() / 2).unwrap();
-        if ordered == update {
+        if ordered == *update {
             part1 += mid;
         } else {
             part2 += mid;
         }
     }
+    Ok((part1, part2))
+}
+
+fn bench(edges: &Edges, updates: &[Update]) {
+    const ROUNDS: usize = 100;
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        solve(updates, |u| order_topo(edges, u)).unwrap();
+    }
+    println!("Topo:      {:?}/round", start.elapsed() / ROUNDS as u32);
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let index = RuleIndex::new(edges);
+        solve(updates, |u| order_indexed(&index, edges, u)).unwrap();
+    }
+    println!("RuleIndex: {:?}/round", start.elapsed() / ROUNDS as u32);
+}
+
+fn main() {
+    let fname = args().nth(1).unwrap();
+    let mode = args().nth(2);
+    let (edges, updates) = parse(&fname);
+    let index = RuleIndex::new(&edges);
+
+    match mode.as_deref() {
+        Some("bench") => bench(&edges, &updates),
+        Some("explain") => {
+            for update in &updates {
+                let exact = index.is_total(update);
+                match order_indexed(&index, &edges, update) {
+                    Some(ordered) if ordered != *update => {
+                        explain(&edges, update, &ordered, exact)
+                    }
+                    Some(_) => {}
+                    None => println!("Update {:?}: rules form a cycle", update),
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let (part1, part2) = solve(&updates, |u| order_indexed(&index, &edges, u)).unwrap();
     println!("Part 1: {}", part1);
     println!("Part 2: {}", part2);
 }
@@ -1,7 +1,10 @@
-use std::collections::HashSet;
+use anyhow::{anyhow, bail, Result};
+use image::{Rgb, RgbImage};
+use std::collections::{HashMap, HashSet};
 use std::env::args;
 use std::fs::read_to_string;
 use std::hash::Hash;
+use std::time::Instant;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 struct Dir {
@@ -11,10 +14,54 @@ struct Dir {
 
 impl Dir {
   const UP: Self = Self { dx: 0, dy: -1 };
+  const ALL: [Self; 4] = [
+    Self::UP,
+    Self { dx: 1, dy: 0 },
+    Self { dx: 0, dy: 1 },
+    Self { dx: -1, dy: 0 },
+  ];
 
   fn turn_right(&self) -> Self {
     Self { dx: -self.dy, dy: self.dx }
   }
+
+  fn index(&self) -> usize {
+    Self::ALL.iter().position(|d| d == self).unwrap()
+  }
+
+  fn from_char(c: char) -> Option<Self> {
+    "^>v<".find(c).map(|i| Self::ALL[i])
+  }
+
+  fn symbol(&self) -> char {
+    "^>v<".as_bytes()[self.index()] as char
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Turn {
+  Right,
+  Left,
+  Reverse,
+}
+
+impl Turn {
+  fn parse(s: &str) -> Result<Self> {
+    match s {
+      "right" => Ok(Self::Right),
+      "left" => Ok(Self::Left),
+      "reverse" => Ok(Self::Reverse),
+      _ => bail!("unknown turn rule {:?}", s),
+    }
+  }
+
+  fn apply(&self, dir: &Dir) -> Dir {
+    match self {
+      Self::Right => dir.turn_right(),
+      Self::Left => dir.turn_right().turn_right().turn_right(),
+      Self::Reverse => dir.turn_right().turn_right(),
+    }
+  }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -28,62 +75,115 @@ impl Pos {
     Self { x: x as i32, y: y as i32 }
   }
 
-  fn empty() -> Self {
-    Self { x: 0, y: 0 }
-  }
-
   fn next(&self, dir: &Dir) -> Self {
     Self {
       x: self.x + dir.dx,
       y: self.y + dir.dy,
     }
   }
+
+  // How many steps in dir it takes to get from self to other, if it's straight ahead
+  fn steps_to(&self, other: &Pos, dir: &Dir) -> Option<i32> {
+    let (ox, oy) = (other.x - self.x, other.y - self.y);
+    let steps = ox * dir.dx + oy * dir.dy;
+    if steps > 0 && ox == dir.dx * steps && oy == dir.dy * steps {
+      Some(steps)
+    } else {
+      None
+    }
+  }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 struct Guard {
   pos: Pos,
   dir: Dir,
+  turn: Turn,
 }
 
 impl Guard {
   fn next_pos(&self) -> Pos {
     self.pos.next(&self.dir)
   }
-  fn turn_right(&mut self) {
-    self.dir = self.dir.turn_right();
+  fn turn(&mut self) {
+    self.dir = self.turn.apply(&self.dir);
   }
 }
 
 type Obstacles = HashSet<Pos>;
 
+// Dense bitmap of guard states, keyed by (pos, dir)
+#[derive(Debug, Clone)]
+struct Visited {
+  width: usize,
+  seen: Vec<bool>,
+}
+
+impl Visited {
+  fn new(width: usize, height: usize) -> Self {
+    Self { width, seen: vec![false; width * height * 4] }
+  }
+
+  fn index(&self, guard: &Guard) -> usize {
+    (guard.pos.y as usize * self.width + guard.pos.x as usize) * 4 + guard.dir.index()
+  }
+
+  // Returns whether the guard state was newly inserted
+  fn insert(&mut self, guard: &Guard) -> bool {
+    let ix = self.index(guard);
+    !std::mem::replace(&mut self.seen[ix], true)
+  }
+
+  fn positions(&self) -> HashSet<Pos> {
+    self
+      .seen
+      .chunks(4)
+      .enumerate()
+      .filter(|(_, dirs)| dirs.iter().any(|v| *v))
+      .map(|(i, _)| Pos::new(i % self.width, i / self.width))
+      .collect()
+  }
+}
+
 #[derive(Debug, Clone)]
 struct Arena {
   width: usize,
   height: usize,
   guard: Guard,
   guard_orig: Pos,
+  guards: Vec<Guard>,
   obstacles: Obstacles,
-  visited: HashSet<Guard>,
+  visited: Visited,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum SimulationResult {
+enum Outcome {
   Repeat,
   Exited,
+  Collided,
+  // Still patrolling when the simulation stopped because of someone else's collision
+  Stopped,
+}
+
+#[derive(Debug, Clone)]
+struct SimulationResult {
+  outcomes: Vec<(Guard, Outcome)>,
+  collisions: Vec<Pos>,
+  steps: usize,
 }
 
 impl Arena {
-  fn new(width: usize, height: usize, guard_pos: Pos, obstacles: Obstacles) -> Self {
+  fn new(width: usize, height: usize, guards: Vec<Guard>, obstacles: Obstacles) -> Self {
     let mut ret = Self {
       width,
       height,
-      guard: Guard { pos: guard_pos, dir: Dir::UP },
-      guard_orig: guard_pos,
+      guard: guards[0],
+      guard_orig: guards[0].pos,
+      guards,
       obstacles,
-      visited: HashSet::new(),
+      visited: Visited::new(width, height),
     };
-    ret.visited.insert(ret.guard);
+    ret.visited.insert(&ret.guard);
     ret
   }
 
@@ -91,19 +191,27 @@ impl Arena {
     pos.x >= 0 && pos.x < self.width as i32 && pos.y >= 0 && pos.y < self.height as i32
   }
 
-  fn simulate(&mut self) -> SimulationResult {
+  fn start(&self) -> Guard {
+    Guard { pos: self.guard_orig, ..self.guards[0] }
+  }
+
+  fn simulate(&mut self) -> Outcome {
     loop {
+      let mut turns = 0;
       while self.obstacles.contains(&self.guard.next_pos()) {
-        self.guard.turn_right();
+        if turns == 4 {
+          return Outcome::Repeat;
+        }
+        self.guard.turn();
+        turns += 1;
       }
       self.guard.pos = self.guard.next_pos();
 
-      if self.visited.contains(&self.guard) {
-        return SimulationResult::Repeat;
-      } else if !self.contains(&self.guard.pos) {
-        return SimulationResult::Exited;
+      if !self.contains(&self.guard.pos) {
+        return Outcome::Exited;
+      } else if !self.visited.insert(&self.guard) {
+        return Outcome::Repeat;
       }
-      self.visited.insert(self.guard);
     }
   }
 
@@ -111,58 +219,348 @@ impl Arena {
     let mut arena = Self {
       width: self.width,
       height: self.height,
-      guard: Guard { pos: self.guard_orig, dir: Dir::UP },
+      guard: self.start(),
       guard_orig: self.guard_orig,
+      guards: self.guards.clone(),
       obstacles: self.obstacles.clone(),
-      visited: HashSet::new(),
+      visited: Visited::new(self.width, self.height),
     };
     arena.obstacles.insert(*pos);
     arena
   }
 
   fn visited_positions(&self) -> HashSet<Pos> {
-    self.visited.iter().map(|g| g.pos).collect()
+    self.visited.positions()
+  }
+
+  // Run every guard at once, one step or turn per tick. Guards treat each other as obstacles, and
+  // the whole simulation stops if two of them step onto the same cell.
+  fn simulate_guards(&self) -> SimulationResult {
+    let mut active: Vec<(usize, Guard)> = self.guards.iter().copied().enumerate().collect();
+    let mut outcomes: Vec<Option<Outcome>> = vec![None; self.guards.len()];
+    let mut seen: HashSet<Vec<(usize, Guard)>> = HashSet::new();
+    let mut steps = 0;
+    let mut collisions = Vec::new();
+
+    while !active.is_empty() {
+      if !seen.insert(active.clone()) {
+        for (i, _) in &active {
+          outcomes[*i] = Some(Outcome::Repeat);
+        }
+        break;
+      }
+
+      let occupied: HashSet<Pos> = active.iter().map(|(_, g)| g.pos).collect();
+      let mut targets: HashMap<Pos, Vec<usize>> = HashMap::new();
+      for (i, guard) in active.iter_mut() {
+        let next = guard.next_pos();
+        if self.obstacles.contains(&next) || occupied.contains(&next) {
+          guard.turn();
+        } else {
+          targets.entry(next).or_default().push(*i);
+        }
+      }
+
+      for (pos, who) in &targets {
+        if who.len() > 1 {
+          collisions.push(*pos);
+          for i in who {
+            outcomes[*i] = Some(Outcome::Collided);
+          }
+        }
+      }
+      if !collisions.is_empty() {
+        for (i, _) in &active {
+          outcomes[*i].get_or_insert(Outcome::Stopped);
+        }
+        break;
+      }
+
+      let moving: HashSet<usize> = targets.values().flatten().copied().collect();
+      for (i, guard) in active.iter_mut() {
+        if moving.contains(i) {
+          guard.pos = guard.next_pos();
+        }
+      }
+      active.retain(|(i, guard)| {
+        let inside = self.contains(&guard.pos);
+        if !inside {
+          outcomes[*i] = Some(Outcome::Exited);
+        }
+        inside
+      });
+      steps += 1;
+    }
+
+    SimulationResult {
+      outcomes: self.guards.iter().copied().zip(outcomes.into_iter().map(Option::unwrap)).collect(),
+      collisions,
+      steps,
+    }
+  }
+
+  // Every state the guard passes through from the start, including turns in place
+  fn route(&self, extra: Option<&Pos>) -> Vec<Guard> {
+    let blocked = |p: &Pos| self.obstacles.contains(p) || extra == Some(p);
+    let mut visited = Visited::new(self.width, self.height);
+    let mut guard = self.start();
+    let mut route = Vec::new();
+    while visited.insert(&guard) {
+      route.push(guard);
+      if blocked(&guard.next_pos()) {
+        guard.turn();
+      } else {
+        guard.pos = guard.next_pos();
+        if !self.contains(&guard.pos) {
+          break;
+        }
+      }
+    }
+    route
+  }
+
+  // Draw the map in the style of the puzzle text. With a selected obstacle, draw the route it
+  // causes instead of the original one.
+  fn render(&self, candidates: &HashSet<Pos>, selected: Option<&Pos>) -> Vec<Vec<char>> {
+    let mut axes: HashMap<Pos, (bool, bool)> = HashMap::new();
+    for guard in self.route(selected) {
+      let axis = axes.entry(guard.pos).or_default();
+      if guard.dir.dx == 0 {
+        axis.0 = true;
+      } else {
+        axis.1 = true;
+      }
+    }
+
+    (0..self.height)
+      .map(|y| {
+        (0..self.width)
+          .map(|x| {
+            let pos = Pos::new(x, y);
+            if self.obstacles.contains(&pos) {
+              '#'
+            } else if let Some(g) = self.guards.iter().find(|g| g.pos == pos) {
+              g.dir.symbol()
+            } else if candidates.contains(&pos) && selected.is_none_or(|s| *s == pos) {
+              'O'
+            } else {
+              match axes.get(&pos) {
+                Some((true, true)) => '+',
+                Some((true, false)) => '|',
+                Some((false, true)) => '-',
+                _ => '.',
+              }
+            }
+          })
+          .collect()
+      })
+      .collect()
+  }
+
+  fn print_render(&self, candidates: &HashSet<Pos>, selected: Option<&Pos>) {
+    for row in self.render(candidates, selected) {
+      println!("{}", row.iter().collect::<String>());
+    }
+  }
+
+  fn save_png(&self, fname: &str, candidates: &HashSet<Pos>, selected: Option<&Pos>) {
+    const SCALE: u32 = 4;
+    let grid = self.render(candidates, selected);
+    let mut img = RgbImage::new(self.width as u32 * SCALE, self.height as u32 * SCALE);
+    for (y, row) in grid.iter().enumerate() {
+      for (x, c) in row.iter().enumerate() {
+        let color = match c {
+          '#' => Rgb([128, 128, 128]),
+          '^' | '>' | 'v' | '<' => Rgb([0, 128, 255]),
+          'O' => Rgb([255, 0, 0]),
+          '+' => Rgb([255, 255, 0]),
+          '|' | '-' => Rgb([255, 255, 255]),
+          _ => Rgb([0, 0, 0]),
+        };
+        for dy in 0..SCALE {
+          for dx in 0..SCALE {
+            img.put_pixel(x as u32 * SCALE + dx, y as u32 * SCALE + dy, color);
+          }
+        }
+      }
+    }
+    img.save(fname).unwrap();
+  }
+}
+
+// For every (pos, dir), where the guard ends up when it next bumps into an obstacle, or None if
+// it walks off the map. Lets the simulation jump from turn to turn instead of stepping.
+struct JumpTable {
+  width: usize,
+  stops: Vec<Option<Pos>>,
+}
+
+impl JumpTable {
+  fn new(arena: &Arena) -> Self {
+    let mut stops = vec![None; arena.width * arena.height * 4];
+    for dir in Dir::ALL {
+      for y in 0..arena.height {
+        for x in 0..arena.width {
+          let start = Pos::new(x, y);
+          let mut pos = start;
+          let stop = loop {
+            let next = pos.next(&dir);
+            if !arena.contains(&next) {
+              break None;
+            } else if arena.obstacles.contains(&next) {
+              break Some(pos);
+            }
+            pos = next;
+          };
+          stops[(y * arena.width + x) * 4 + dir.index()] = stop;
+        }
+      }
+    }
+    Self { width: arena.width, stops }
+  }
+
+  fn stop(&self, guard: &Guard) -> Option<Pos> {
+    self.stops[(guard.pos.y as usize * self.width + guard.pos.x as usize) * 4 + guard.dir.index()]
+  }
+
+  // Like stop(), but with one extra obstacle overlaid on the map
+  fn stop_with(&self, guard: &Guard, extra: &Pos) -> Option<Pos> {
+    let stop = self.stop(guard);
+    let Some(steps) = guard.pos.steps_to(extra, &guard.dir) else {
+      return stop;
+    };
+    let before_stop = match stop {
+      None => true,
+      Some(s) => steps <= guard.pos.steps_to(&s, &guard.dir).unwrap_or(0) + 1,
+    };
+    if before_stop {
+      Some(Pos { x: extra.x - guard.dir.dx, y: extra.y - guard.dir.dy })
+    } else {
+      stop
+    }
+  }
+
+  fn loops_with(&self, arena: &Arena, extra: &Pos) -> bool {
+    let mut visited = Visited::new(arena.width, arena.height);
+    let mut guard = arena.start();
+    while let Some(pos) = self.stop_with(&guard, extra) {
+      guard.pos = pos;
+      if !visited.insert(&guard) {
+        return true;
+      }
+      guard.turn();
+    }
+    false
   }
 }
 
-fn parse(fname: &str) -> Arena {
-  let contents = read_to_string(fname).unwrap();
+fn parse(fname: &str) -> Result<Arena> {
+  let contents = read_to_string(fname)?;
 
   let mut width = 0;
   let mut height = 0;
-  let mut guard_pos = Pos::empty();
+  let mut guards = Vec::new();
   let mut obstacles = Obstacles::new();
 
-  for (y, line) in contents.lines().enumerate() {
+  let mut lines = contents.lines();
+  for (y, line) in lines.by_ref().take_while(|l| !l.is_empty()).enumerate() {
     width = line.len();
     for (x, c) in line.chars().enumerate() {
       if c == '#' {
         obstacles.insert(Pos::new(x, y));
-      } else if c == '^' {
-        guard_pos = Pos::new(x, y);
+      } else if let Some(dir) = Dir::from_char(c) {
+        guards.push(Guard { pos: Pos::new(x, y), dir, turn: Turn::Right });
       }
     }
     height = y + 1;
-    }
-  Arena::new(width, height, guard_pos, obstacles)
+  }
+
+  if guards.is_empty() {
+    bail!("no guard on the map");
+  }
+
+  // Optional turn rules after the map, like "3,4 left"
+  for line in lines.map(str::trim).filter(|l| !l.is_empty()) {
+    let (pos, turn) = line.split_once(' ').ok_or_else(|| anyhow!("bad turn rule {:?}", line))?;
+    let pos = parse_pos(pos)?;
+    let guard = guards
+      .iter_mut()
+      .find(|g| g.pos == pos)
+      .ok_or_else(|| anyhow!("no guard at {:?} for turn rule", pos))?;
+    guard.turn = Turn::parse(turn)?;
+  }
+  Ok(Arena::new(width, height, guards, obstacles))
+}
+
+fn part2_stepping(arena: &Arena, candidates: &HashSet<Pos>) -> usize {
+  candidates
+    .iter()
+    .filter(|p| {
+      **p != arena.guard_orig && arena.with_obstacle(p).simulate() == Outcome::Repeat
+    })
+    .count()
+}
+
+fn loop_obstacles(arena: &Arena, candidates: &HashSet<Pos>) -> HashSet<Pos> {
+  let jumps = JumpTable::new(arena);
+  candidates
+    .iter()
+    .filter(|p| **p != arena.guard_orig && jumps.loops_with(arena, p))
+    .copied()
+    .collect()
+}
+
+fn part2_jumping(arena: &Arena, candidates: &HashSet<Pos>) -> usize {
+  loop_obstacles(arena, candidates).len()
+}
+
+fn parse_pos(s: &str) -> Result<Pos> {
+  let (x, y) = s.split_once(',').ok_or_else(|| anyhow!("bad position {:?}", s))?;
+  Ok(Pos::new(x.parse()?, y.parse()?))
 }
 
 fn main() {
   let fname = args().nth(1).unwrap();
+  let mode = args().nth(2);
 
-  let mut arena = parse(&fname);
-  let orig_pos = arena.guard.pos.clone();
+  let mut arena = parse(&fname).unwrap();
+  let orig = arena.clone();
   arena.simulate();
   let visited = arena.visited_positions();
   println!("Part 1: {}", visited.len());
 
-  let part2 = visited.iter().filter(|p| {
-    if **p == orig_pos {
-      false
-    } else {
-      let mut arena2 = arena.with_obstacle(p);
-      arena2.simulate() == SimulationResult::Repeat
+  let loops = loop_obstacles(&orig, &visited);
+  match mode.as_deref() {
+    Some("render") => {
+      let selected = args().nth(3).map(|s| parse_pos(&s).unwrap());
+      orig.print_render(&loops, selected.as_ref());
+    }
+    Some("png") => {
+      let out = args().nth(3).unwrap();
+      let selected = args().nth(4).map(|s| parse_pos(&s).unwrap());
+      orig.save_png(&out, &loops, selected.as_ref());
     }
-  }).count();
-  println!("Part 2: {}", part2);
+    Some("guards") => {
+      let result = orig.simulate_guards();
+      for (guard, outcome) in &result.outcomes {
+        println!("Guard at {},{}: {:?}", guard.pos.x, guard.pos.y, outcome);
+      }
+      for pos in &result.collisions {
+        println!("Collision at {},{}", pos.x, pos.y);
+      }
+      println!("Stopped after {} steps", result.steps);
+    }
+    _ => {}
+  }
+
+  if mode.as_deref() == Some("bench") {
+    let start = Instant::now();
+    let stepping = part2_stepping(&orig, &visited);
+    println!("Stepping: {} in {:?}", stepping, start.elapsed());
+    let start = Instant::now();
+    let jumping = part2_jumping(&orig, &visited);
+    println!("Jumping:  {} in {:?}", jumping, start.elapsed());
+  }
+
+  println!("Part 2: {}", loops.len());
 }
@@ -1,5 +1,146 @@
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
 use std::env::args;
 use std::fs::read_to_string;
+use std::time::Instant;
+
+trait Operator {
+    fn name(&self) -> &'static str;
+    fn symbol(&self) -> &'static str;
+    // None if the result isn't representable
+    fn apply(&self, a: i64, b: i64) -> Option<i64>;
+}
+
+struct Add;
+struct Mul;
+struct Concat;
+struct Sub;
+struct Div;
+struct Xor;
+struct Pow;
+
+impl Operator for Add {
+    fn name(&self) -> &'static str {
+        "add"
+    }
+    fn symbol(&self) -> &'static str {
+        "+"
+    }
+    fn apply(&self, a: i64, b: i64) -> Option<i64> {
+        a.checked_add(b)
+    }
+}
+
+impl Operator for Mul {
+    fn name(&self) -> &'static str {
+        "mul"
+    }
+    fn symbol(&self) -> &'static str {
+        "*"
+    }
+    fn apply(&self, a: i64, b: i64) -> Option<i64> {
+        a.checked_mul(b)
+    }
+}
+
+impl Operator for Concat {
+    fn name(&self) -> &'static str {
+        "concat"
+    }
+    fn symbol(&self) -> &'static str {
+        "||"
+    }
+    fn apply(&self, a: i64, b: i64) -> Option<i64> {
+        if a < 0 || b < 0 {
+            return None;
+        }
+        let bdigits = b.checked_ilog10().unwrap_or(0) + 1;
+        a.checked_mul(10_i64.checked_pow(bdigits)?)?.checked_add(b)
+    }
+}
+
+impl Operator for Sub {
+    fn name(&self) -> &'static str {
+        "sub"
+    }
+    fn symbol(&self) -> &'static str {
+        "-"
+    }
+    fn apply(&self, a: i64, b: i64) -> Option<i64> {
+        a.checked_sub(b)
+    }
+}
+
+impl Operator for Div {
+    fn name(&self) -> &'static str {
+        "div"
+    }
+    fn symbol(&self) -> &'static str {
+        "/"
+    }
+    fn apply(&self, a: i64, b: i64) -> Option<i64> {
+        a.checked_div(b)
+    }
+}
+
+impl Operator for Xor {
+    fn name(&self) -> &'static str {
+        "xor"
+    }
+    fn symbol(&self) -> &'static str {
+        "^"
+    }
+    fn apply(&self, a: i64, b: i64) -> Option<i64> {
+        Some(a ^ b)
+    }
+}
+
+impl Operator for Pow {
+    fn name(&self) -> &'static str {
+        "pow"
+    }
+    fn symbol(&self) -> &'static str {
+        "**"
+    }
+    fn apply(&self, a: i64, b: i64) -> Option<i64> {
+        a.checked_pow(b.try_into().ok()?)
+    }
+}
+
+const BUILTINS: &[&dyn Operator] = &[&Add, &Mul, &Concat, &Sub, &Div, &Xor, &Pow];
+
+type Operators = Vec<&'static dyn Operator>;
+
+const MODES: &[&str] = &["backwards", "bench", "check"];
+
+fn operators(names: &str) -> Result<Operators> {
+    names
+        .split(',')
+        .map(|name| {
+            BUILTINS
+                .iter()
+                .find(|op| op.name() == name)
+                .copied()
+                .ok_or_else(|| {
+                    let ops = BUILTINS.iter().map(|op| op.name()).join(", ");
+                    anyhow!(
+                        "unknown mode or operator {:?}: modes are {}, operators are {}",
+                        name,
+                        MODES.join(", "),
+                        ops
+                    )
+                })
+        })
+        .collect()
+}
+
+fn standard_operators(allow_concat: bool) -> Operators {
+    if allow_concat {
+        operators("add,mul,concat").unwrap()
+    } else {
+        operators("add,mul").unwrap()
+    }
+}
 
 #[derive(Debug)]
 struct Equation {
@@ -7,28 +148,99 @@ struct Equation {
     operands: Vec<i64>,
 }
 
-fn concat(a: i64, b: i64) -> i64 {
-    let bdigits = b.checked_ilog10().unwrap_or(0) + 1;
-    a * 10_i64.pow(bdigits) + b
-}
-
 impl Equation {
-    fn soluble_inner(&self, allow_concat: bool, idx: usize, val: i64) -> bool {
+    // Calls found with the indices of each working operator sequence, until it returns true
+    fn search(
+        &self,
+        ops: &Operators,
+        idx: usize,
+        val: i64,
+        seq: &mut Vec<usize>,
+        found: &mut dyn FnMut(&[usize]) -> bool,
+    ) -> bool {
         match self.operands.get(idx) {
-            None => self.result == val,
-            Some(n) => {
-                let mut nvs = vec![val + n, val * n];
-                if allow_concat {
-                    nvs.push(concat(val, *n))
-                }
-                nvs.iter()
-                    .any(|nv| self.soluble_inner(allow_concat, idx + 1, *nv))
-            }
+            None => self.result == val && found(seq),
+            Some(n) => ops.iter().enumerate().any(|(i, op)| {
+                let Some(nv) = op.apply(val, *n) else {
+                    return false;
+                };
+                seq.push(i);
+                let done = self.search(ops, idx + 1, nv, seq, found);
+                seq.pop();
+                done
+            }),
         }
     }
 
+    fn solution(&self, ops: &Operators) -> Option<Operators> {
+        let mut ret = None;
+        self.search(ops, 1, self.operands[0], &mut Vec::new(), &mut |seq| {
+            ret = Some(seq.iter().map(|i| ops[*i]).collect());
+            true
+        });
+        ret
+    }
+
+    fn count_solutions(&self, ops: &Operators) -> usize {
+        let mut count = 0;
+        self.search(ops, 1, self.operands[0], &mut Vec::new(), &mut |_| {
+            count += 1;
+            false
+        });
+        count
+    }
+
     fn soluble(&self, allow_concat: bool) -> bool {
-        self.soluble_inner(allow_concat, 1, *self.operands.get(0).unwrap())
+        self.solution(&standard_operators(allow_concat)).is_some()
+    }
+
+    // Undo operators from the last operand back to the first, only following inverses that are
+    // exact. Much narrower than the forward search, since most branches fail immediately.
+    fn soluble_backwards_inner(&self, allow_concat: bool, idx: usize, target: i64) -> bool {
+        let n = self.operands[idx];
+        if idx == 0 {
+            return target == n;
+        }
+        if target < 0 {
+            return false;
+        }
+        if n == 0 && target == 0 {
+            return true;
+        }
+        if self.soluble_backwards_inner(allow_concat, idx - 1, target - n) {
+            return true;
+        }
+        if n != 0
+            && target % n == 0
+            && self.soluble_backwards_inner(allow_concat, idx - 1, target / n)
+        {
+            return true;
+        }
+        // No power of ten fits past 10^18, and then n is longer than any target anyway
+        if let Some(pow) = allow_concat
+            .then(|| 10_i64.checked_pow(n.checked_ilog10().unwrap_or(0) + 1))
+            .flatten()
+        {
+            if target % pow == n
+                && self.soluble_backwards_inner(allow_concat, idx - 1, target / pow)
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn soluble_backwards(&self, allow_concat: bool) -> bool {
+        self.soluble_backwards_inner(allow_concat, self.operands.len() - 1, self.result)
+    }
+
+    // Evaluated strictly left to right, as in the puzzle
+    fn expression(&self, seq: &Operators) -> String {
+        let mut ret = self.operands[0].to_string();
+        for (op, n) in seq.iter().zip(&self.operands[1..]) {
+            ret.push_str(&format!(" {} {}", op.symbol(), n));
+        }
+        ret
     }
 }
 
@@ -50,7 +262,7 @@ fn parse(fname: &str) -> Vec<Equation> {
         .collect()
 }
 
-fn solve(equations: &Vec<Equation>, allow_concat: bool) -> i64 {
+fn solve(equations: &[Equation], allow_concat: bool) -> i64 {
     let mut tot = 0;
     for eq in equations {
         if eq.soluble(allow_concat) {
@@ -60,10 +272,108 @@ fn solve(equations: &Vec<Equation>, allow_concat: bool) -> i64 {
     tot
 }
 
+fn solve_backwards(equations: &[Equation], allow_concat: bool) -> i64 {
+    equations
+        .iter()
+        .filter(|eq| eq.soluble_backwards(allow_concat))
+        .map(|eq| eq.result)
+        .sum()
+}
+
+fn bench(equations: &[Equation]) {
+    for allow_concat in [false, true] {
+        let start = Instant::now();
+        let forward = solve(equations, allow_concat);
+        let forward_time = start.elapsed();
+        let start = Instant::now();
+        let backward = solve_backwards(equations, allow_concat);
+        let backward_time = start.elapsed();
+        println!(
+            "concat={}: forward {} in {:?}, backward {} in {:?}",
+            allow_concat, forward, forward_time, backward, backward_time
+        );
+    }
+}
+
+// Compare both solvers on lots of random equations. Results are built from random operators
+// half the time, so plenty of them are soluble.
+fn check(rounds: usize) {
+    let mut seed: u64 = 0x2024;
+    let mut rand = |n: u64| {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) % n
+    };
+
+    for round in 0..rounds {
+        let len = 1 + rand(7) as usize;
+        let operands: Vec<i64> = (0..len).map(|_| rand(30) as i64).collect();
+        let ops = standard_operators(true);
+        let mut result = operands[0];
+        for n in &operands[1..] {
+            result = ops[rand(3) as usize].apply(result, *n).unwrap_or(result);
+        }
+        if rand(2) == 0 {
+            result += rand(5) as i64;
+        }
+        let equations = [Equation { result, operands }];
+        for allow_concat in [false, true] {
+            let forward = solve(&equations, allow_concat);
+            let backward = solve_backwards(&equations, allow_concat);
+            assert_eq!(forward, backward, "round {}: {:?}", round, equations[0]);
+        }
+    }
+    println!("{} random equations agree", rounds);
+}
+
+fn show(equations: &[Equation], ops: &Operators) {
+    let mut tot = 0;
+    for eq in equations {
+        if let Some(seq) = eq.solution(ops) {
+            println!(
+                "{} = {} ({} solutions)",
+                eq.result,
+                eq.expression(&seq),
+                eq.count_solutions(ops)
+            );
+            tot += eq.result;
+        }
+    }
+    println!("Total: {}", tot);
+}
+
 fn main() {
     let fname = args().nth(1).unwrap();
     let equations = parse(&fname);
 
-    println!("Part 1: {}", solve(&equations, false));
-    println!("Part 2: {}", solve(&equations, true));
+    match args().nth(2).as_deref() {
+        Some("backwards") => {
+            println!("Part 1: {}", solve_backwards(&equations, false));
+            println!("Part 2: {}", solve_backwards(&equations, true));
+        }
+        Some("bench") => bench(&equations),
+        Some("check") => check(100000),
+        Some(names) => match operators(names) {
+            Ok(ops) => show(&equations, &ops),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            println!("Part 1: {}", solve(&equations, false));
+            println!("Part 2: {}", solve(&equations, true));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backwards_matches_forward() {
+        check(5000);
+    }
 }
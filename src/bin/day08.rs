@@ -1,5 +1,6 @@
+use image::{GrayImage, Luma};
 use itertools::Itertools;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env::args;
 use std::fs::read_to_string;
 
@@ -21,6 +22,150 @@ struct LabeledPos {
     label: char,
 }
 
+// Which antennas may resonate with each other
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Pairing {
+    Same,
+    Cross,
+    Any,
+}
+
+impl Pairing {
+    fn parse(s: &str) -> Self {
+        match s {
+            "same" => Self::Same,
+            "cross" => Self::Cross,
+            "any" => Self::Any,
+            _ => panic!("Unknown pairing {}", s),
+        }
+    }
+}
+
+trait AntinodeRule {
+    fn name(&self) -> &'static str;
+    // How many antennas resonate together
+    fn arity(&self) -> usize {
+        2
+    }
+    // Antinodes for one group of antennas, possibly out of bounds
+    fn antinodes(&self, city: &City, antennas: &[Pos]) -> Vec<Pos>;
+}
+
+// Part 1: one antinode beyond each antenna, at the same distance as the other one
+struct Doubled;
+// Part 2: every integer multiple of the offset along the line
+struct Harmonic;
+// Halfway between two antennas, if that lands on the grid
+struct Midpoint;
+// A third and two thirds of the way between two antennas, if those land on the grid
+struct Trisection;
+// The whole line through any three collinear antennas
+struct Collinear;
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a.abs()
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// Point p + (q - p) * num / den, if it's a lattice point
+fn lattice_point(p: &Pos, q: &Pos, num: i64, den: i64) -> Option<Pos> {
+    let dx = (q.x - p.x) * num;
+    let dy = (q.y - p.y) * num;
+    if dx % den == 0 && dy % den == 0 {
+        Some(Pos::new(p.x + dx / den, p.y + dy / den))
+    } else {
+        None
+    }
+}
+
+// Points p + m * step for m = 0, 1, 2, ... while still in the city
+fn ray(city: &City, p: &Pos, step: &Pos) -> Vec<Pos> {
+    (0..)
+        .map(|m| Pos::new(p.x + m * step.x, p.y + m * step.y))
+        .take_while(|pos| city.in_bounds(pos))
+        .collect()
+}
+
+impl AntinodeRule for Doubled {
+    fn name(&self) -> &'static str {
+        "doubled"
+    }
+    fn antinodes(&self, _city: &City, antennas: &[Pos]) -> Vec<Pos> {
+        let (a, b) = (antennas[0], antennas[1]);
+        vec![
+            Pos::new(2 * a.x - b.x, 2 * a.y - b.y),
+            Pos::new(2 * b.x - a.x, 2 * b.y - a.y),
+        ]
+    }
+}
+
+impl AntinodeRule for Harmonic {
+    fn name(&self) -> &'static str {
+        "harmonic"
+    }
+    fn antinodes(&self, city: &City, antennas: &[Pos]) -> Vec<Pos> {
+        let (a, b) = (antennas[0], antennas[1]);
+        let mut ret = ray(city, &a, &Pos::new(a.x - b.x, a.y - b.y));
+        ret.extend(ray(city, &b, &Pos::new(b.x - a.x, b.y - a.y)));
+        ret
+    }
+}
+
+impl AntinodeRule for Midpoint {
+    fn name(&self) -> &'static str {
+        "midpoint"
+    }
+    fn antinodes(&self, _city: &City, antennas: &[Pos]) -> Vec<Pos> {
+        lattice_point(&antennas[0], &antennas[1], 1, 2)
+            .into_iter()
+            .collect()
+    }
+}
+
+impl AntinodeRule for Trisection {
+    fn name(&self) -> &'static str {
+        "trisection"
+    }
+    fn antinodes(&self, _city: &City, antennas: &[Pos]) -> Vec<Pos> {
+        [1, 2]
+            .iter()
+            .filter_map(|num| lattice_point(&antennas[0], &antennas[1], *num, 3))
+            .collect()
+    }
+}
+
+impl AntinodeRule for Collinear {
+    fn name(&self) -> &'static str {
+        "collinear"
+    }
+    fn arity(&self) -> usize {
+        3
+    }
+    fn antinodes(&self, city: &City, antennas: &[Pos]) -> Vec<Pos> {
+        let (a, b, c) = (antennas[0], antennas[1], antennas[2]);
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        if dx * (c.y - a.y) != dy * (c.x - a.x) {
+            return Vec::new();
+        }
+        let g = gcd(dx, dy);
+        let mut ret = ray(city, &a, &Pos::new(dx / g, dy / g));
+        ret.extend(ray(city, &a, &Pos::new(-dx / g, -dy / g)));
+        ret
+    }
+}
+
+const RULES: &[&dyn AntinodeRule] = &[&Doubled, &Harmonic, &Midpoint, &Trisection, &Collinear];
+
+fn rule(name: &str) -> &'static dyn AntinodeRule {
+    *RULES
+        .iter()
+        .find(|r| r.name() == name)
+        .unwrap_or_else(|| panic!("Unknown antinode rule {}", name))
+}
+
 #[derive(Debug, Clone)]
 struct City {
     width: usize,
@@ -58,51 +203,97 @@ impl City {
         pos.x >= 0 && pos.x < (self.width as i64) && pos.y >= 0 && pos.y < (self.height as i64)
     }
 
-    fn antinodes_pair<T: Iterator<Item = i64> + Clone>(
-        &self,
-        range: T,
-        near: &LabeledPos,
-        far: &LabeledPos,
-    ) -> HashSet<LabeledPos> {
-        let dx = near.pos.x - far.pos.x;
-        let dy = near.pos.y - far.pos.y;
-        let mut ret = HashSet::new();
-
-        for m in range {
-            let pos = Pos::new(near.pos.x + m * dx, near.pos.y + m * dy);
-            if !self.in_bounds(&pos) {
-                break;
-            }
-            ret.insert(LabeledPos {
-                pos,
-                label: near.label,
-            });
+    // Every combination of antennas the rule cares about, with the label its antinodes get
+    fn groups(&self, arity: usize, pairing: Pairing) -> Vec<(char, Vec<Pos>)> {
+        let mut ret = Vec::new();
+        for combo in self.antennas.iter().combinations(arity) {
+            let label = combo[0].label;
+            let same = combo.iter().all(|a| a.label == label);
+            let label = match pairing {
+                Pairing::Same if same => label,
+                Pairing::Cross if !same => '*',
+                Pairing::Any if same => label,
+                Pairing::Any => '*',
+                _ => continue,
+            };
+            ret.push((label, combo.iter().map(|a| a.pos).collect()));
         }
         ret
     }
 
-    fn antinodes_all<T: Iterator<Item = i64> + Clone>(&self, range: T) -> HashSet<LabeledPos> {
+    fn antinodes_all(&self, rule: &dyn AntinodeRule, pairing: Pairing) -> HashSet<LabeledPos> {
         let mut ret = HashSet::new();
-        let by_freq = self.antennas.iter().into_group_map_by(|a| a.label);
-        for aas in by_freq.values() {
-            for (a1, a2) in aas.iter().tuple_combinations() {
-                ret.extend(self.antinodes_pair(range.clone(), a1, a2));
-                ret.extend(self.antinodes_pair(range.clone(), a2, a1));
+        for (label, group) in self.groups(rule.arity(), pairing) {
+            for pos in rule.antinodes(self, &group) {
+                if self.in_bounds(&pos) {
+                    ret.insert(LabeledPos { pos, label });
+                }
             }
         }
         ret
     }
 
-    fn solve<T: Iterator<Item = i64> + Clone>(&self, range: T) -> usize {
-        self.antinodes_all(range)
+    fn solve(&self, rule: &dyn AntinodeRule, pairing: Pairing) -> usize {
+        self.antinodes_all(rule, pairing)
             .iter()
             .unique_by(|a| a.pos)
             .count()
     }
 
-    fn print_solution<T: Iterator<Item = i64> + Clone>(&self, range: T) {
+    // Which frequencies have an antinode at each cell
+    fn coverage(&self, rule: &dyn AntinodeRule) -> HashMap<Pos, HashSet<char>> {
+        let mut ret: HashMap<Pos, HashSet<char>> = HashMap::new();
+        for a in self.antinodes_all(rule, Pairing::Same) {
+            ret.entry(a.pos).or_default().insert(a.label);
+        }
+        ret
+    }
+
+    fn print_report(&self, rule: &dyn AntinodeRule) {
+        let coverage = self.coverage(rule);
+        let antennas = self.antennas.iter().counts_by(|a| a.label);
+        let mut antinodes: BTreeMap<char, (usize, usize)> = BTreeMap::new();
+        for labels in coverage.values() {
+            for label in labels {
+                let entry = antinodes.entry(*label).or_default();
+                entry.0 += 1;
+                if labels.len() > 1 {
+                    entry.1 += 1;
+                }
+            }
+        }
+
+        println!("{}:", rule.name());
+        println!("  freq  antennas  antinodes  overlaps");
+        for label in antennas.keys().sorted() {
+            let (count, overlaps) = antinodes.get(label).copied().unwrap_or_default();
+            println!(
+                "  {:>4}  {:>8}  {:>9}  {:>8}",
+                label, antennas[label], count, overlaps
+            );
+        }
+        println!("  total antinodes: {}", coverage.len());
+    }
+
+    fn save_heatmap(&self, rule: &dyn AntinodeRule, fname: &str) {
+        const SCALE: u32 = 4;
+        let coverage = self.coverage(rule);
+        let max = coverage.values().map(|l| l.len()).max().unwrap_or(1);
+        let mut img = GrayImage::new(self.width as u32 * SCALE, self.height as u32 * SCALE);
+        for (pos, labels) in &coverage {
+            let shade = Luma([(labels.len() * 255 / max) as u8]);
+            for dy in 0..SCALE {
+                for dx in 0..SCALE {
+                    img.put_pixel(pos.x as u32 * SCALE + dx, pos.y as u32 * SCALE + dy, shade);
+                }
+            }
+        }
+        img.save(fname).unwrap();
+    }
+
+    fn print_solution(&self, rule: &dyn AntinodeRule, pairing: Pairing) {
         let mut points = HashMap::new();
-        for a in self.antinodes_all(range) {
+        for a in self.antinodes_all(rule, pairing) {
             points.insert(a.pos, '#');
         }
         for a in &self.antennas {
@@ -117,7 +308,7 @@ impl City {
                 };
                 print!("{}", c);
             }
-            print!("\n");
+            println!();
         }
     }
 }
@@ -125,7 +316,28 @@ impl City {
 fn main() {
     let fname = args().nth(1).unwrap();
     let city = City::parse(&fname);
-    println!("Part 1: {}", city.solve(1..2));
-    println!("Part 2: {}", city.solve(0_i64..));
-    city.print_solution(1..2);
+
+    match args().nth(2).as_deref() {
+        Some("report") => {
+            city.print_report(&Doubled);
+            city.print_report(&Harmonic);
+        }
+        Some("heatmap") => {
+            let prefix = args().nth(3).unwrap();
+            for rule in [&Doubled as &dyn AntinodeRule, &Harmonic] {
+                city.save_heatmap(rule, &format!("{}-{}.png", prefix, rule.name()));
+            }
+        }
+        Some(name) => {
+            let rule = rule(name);
+            let pairing = args().nth(3).map_or(Pairing::Same, |p| Pairing::parse(&p));
+            println!("{}: {}", rule.name(), city.solve(rule, pairing));
+            city.print_solution(rule, pairing);
+        }
+        None => {
+            println!("Part 1: {}", city.solve(&Doubled, Pairing::Same));
+            println!("Part 2: {}", city.solve(&Harmonic, Pairing::Same));
+            city.print_solution(&Doubled, Pairing::Same);
+        }
+    }
 }
@@ -1,109 +1,279 @@
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
 use std::env::args;
 use std::fmt;
 use std::fs;
 
-type Bitmap = Vec<Option<i64>>;
-
 #[derive(Clone)]
 struct Gap {
     pos: usize,
     size: usize,
 }
 
+// One contiguous extent of a file. After block-level compaction a file may be split over several.
 #[derive(Clone)]
 struct File {
-    #[allow(unused)]
     id: i64,
     pos: usize,
     size: usize,
 }
 
+impl File {
+    fn checksum(&self) -> i64 {
+        // Sum of id * block for blocks pos..pos+size
+        let (pos, size) = (self.pos as i64, self.size as i64);
+        self.id * (size * pos + size * (size - 1) / 2)
+    }
+}
+
+// Free gaps indexed by size, each a min-heap of positions
+struct FreeIndex {
+    by_size: Vec<BinaryHeap<Reverse<usize>>>,
+}
+
+impl FreeIndex {
+    const MAX_SIZE: usize = 9;
+
+    fn new(gaps: &[Gap]) -> Self {
+        let mut by_size = vec![BinaryHeap::new(); Self::MAX_SIZE + 1];
+        for gap in gaps {
+            if gap.size > 0 {
+                by_size[gap.size].push(Reverse(gap.pos));
+            }
+        }
+        Self { by_size }
+    }
+
+    // Take the leftmost gap that fits size blocks and is before limit
+    fn take(&mut self, size: usize, limit: usize) -> Option<Gap> {
+        let (gap_size, Reverse(pos)) = (size..=Self::MAX_SIZE)
+            .filter_map(|s| self.by_size[s].peek().map(|p| (s, *p)))
+            .min_by_key(|(_, Reverse(pos))| *pos)?;
+        if pos >= limit {
+            return None;
+        }
+        self.by_size[gap_size].pop();
+        Some(Gap {
+            pos,
+            size: gap_size,
+        })
+    }
+
+    fn put(&mut self, gap: Gap) {
+        if gap.size > 0 {
+            self.by_size[gap.size].push(Reverse(gap.pos));
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Disk {
-    bitmap: Bitmap,
     files: Vec<File>,
     gaps: Vec<Gap>,
+    len: usize,
 }
 
 impl Disk {
-    fn parse(fname: &str) -> Self {
-        let contents = fs::read_to_string(fname).unwrap();
-        let mut bitmap = Bitmap::new();
+    fn parse(fname: &str) -> Result<Self> {
+        Self::parse_map(&fs::read_to_string(fname)?)
+    }
+
+    fn parse_map(contents: &str) -> Result<Self> {
         let mut files = Vec::new();
         let mut gaps = Vec::new();
+        let mut pos = 0;
         let mut next_id = 0;
         let mut next_is_block = true;
-        for c in contents.chars() {
-            let size = c.to_digit(10).unwrap();
-            let n = if next_is_block {
+        for (offset, c) in contents.trim_end().char_indices() {
+            let size = c
+                .to_digit(10)
+                .ok_or_else(|| anyhow!("invalid character {:?} at offset {}", c, offset))?
+                as usize;
+            if next_is_block {
                 files.push(File {
                     id: next_id,
-                    pos: bitmap.len(),
-                    size: size as usize,
-                });
-                Some(next_id)
-            } else {
-                gaps.push(Gap {
-                    pos: bitmap.len(),
-                    size: size as usize,
+                    pos,
+                    size,
                 });
                 next_id += 1;
-                None
-            };
-            for _ in 0..size {
-                bitmap.push(n);
+            } else {
+                gaps.push(Gap { pos, size });
             }
+            pos += size;
             next_is_block = !next_is_block;
         }
-        Self {
-            bitmap,
+        Ok(Self {
             files,
             gaps,
-        }
+            len: pos,
+        })
     }
 
-    fn compact1(&mut self) {
-        let mut start = 0;
-        let mut end = self.bitmap.len() - 1;
-        while start < end {
-            if self.bitmap.get(start).unwrap().is_some() {
-                start += 1;
-            } else if self.bitmap.get(end).unwrap().is_none() {
-                end -= 1;
-            } else {
-                self.bitmap.swap(start, end);
+    // Write the current layout back out as a dense disk map. The format has no room for IDs, so
+    // extents are renumbered in order; the returned Vec maps each new ID to the original one, or
+    // None for the empty files used to split gaps over 9 blocks. Free space at the end is dropped.
+    fn to_map(&self) -> (String, Vec<Option<i64>>) {
+        // Fragments of the same file that ended up next to each other are one extent
+        let mut extents: Vec<File> = Vec::new();
+        for file in self.files.iter().sorted_by_key(|f| f.pos) {
+            match extents.last_mut() {
+                Some(last) if last.id == file.id && last.pos + last.size == file.pos => {
+                    last.size += file.size;
+                }
+                _ => extents.push(file.clone()),
             }
         }
+
+        let mut map = String::new();
+        let mut ids = Vec::new();
+        let mut next = 0;
+        for file in extents {
+            // The map has to start with a file, so an empty one stands in before a leading gap
+            if ids.is_empty() && file.pos > 0 {
+                map.push('0');
+                ids.push(None);
+            }
+            if !ids.is_empty() {
+                let mut gap = file.pos - next;
+                while gap > 9 {
+                    map.push_str("90");
+                    ids.push(None);
+                    gap -= 9;
+                }
+                map.push_str(&gap.to_string());
+            }
+            map.push_str(&file.size.to_string());
+            ids.push(Some(file.id));
+            next = file.pos + file.size;
+        }
+        (map, ids)
     }
 
-    fn move_file_in_bitmap(bitmap: &mut Bitmap, file: &File, gap: &mut Gap) {
-        for i in 0..file.size {
-            bitmap.swap(gap.pos + i, file.pos + i);
+    fn blocks_by_id(&self) -> BTreeMap<i64, usize> {
+        let mut ret = BTreeMap::new();
+        // Empty files can be dropped without losing anything
+        for file in self.files.iter().filter(|f| f.size > 0) {
+            *ret.entry(file.id).or_default() += file.size;
         }
-        gap.pos += file.size;
-        gap.size -= file.size;
+        ret
     }
 
-    fn compact2(&mut self) {
-        for file in self.files.iter().rev() {
-            if let Some(gap) = self
-                .gaps
-                .iter_mut()
-                .find(|g| g.pos < file.pos && g.size >= file.size)
-            {
-                Self::move_file_in_bitmap(&mut self.bitmap, file, gap);
+    // Make sure compaction kept exactly the same blocks for every file
+    fn check(&self, before: &Disk) -> Result<()> {
+        let (now, orig) = (self.blocks_by_id(), before.blocks_by_id());
+        let bad = orig
+            .keys()
+            .chain(now.keys())
+            .unique()
+            .filter(|id| now.get(id) != orig.get(id))
+            .map(|id| {
+                format!(
+                    "file {} had {} blocks, now {}",
+                    id,
+                    orig.get(id).unwrap_or(&0),
+                    now.get(id).unwrap_or(&0)
+                )
+            })
+            .collect_vec();
+        if bad.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(bad.join("\n")))
+        }
+    }
+
+    // Returns the number of blocks moved
+    fn compact1(&mut self) -> usize {
+        let mut moved = Vec::new();
+        let mut blocks = 0;
+        for gap in self.gaps.iter_mut() {
+            while gap.size > 0 {
+                let Some(file) = self.files.last_mut().filter(|f| f.pos > gap.pos) else {
+                    break;
+                };
+                if file.size == 0 {
+                    self.files.pop();
+                    continue;
+                }
+                // Take blocks from the end of the last file
+                let n = gap.size.min(file.size);
+                moved.push(File {
+                    id: file.id,
+                    pos: gap.pos,
+                    size: n,
+                });
+                file.size -= n;
+                blocks += n;
+                gap.pos += n;
+                gap.size -= n;
+                if file.size == 0 {
+                    self.files.pop();
+                }
             }
         }
+        self.files.extend(moved);
+        self.files.sort_by_key(|f| f.pos);
+        blocks
     }
 
-    fn score1(&self) -> i64 {
-        let mut tot = 0;
-        for (i, v) in self.bitmap.iter().enumerate() {
-            if let Some(id) = v {
-                tot += (i as i64) * id;
+    // Returns the number of blocks moved
+    fn compact2(&mut self) -> usize {
+        let mut free = FreeIndex::new(&self.gaps);
+        let mut blocks = 0;
+        for file in self.files.iter_mut().rev() {
+            if let Some(gap) = free.take(file.size, file.pos) {
+                file.pos = gap.pos;
+                blocks += file.size;
+                free.put(Gap {
+                    pos: gap.pos + file.size,
+                    size: gap.size - file.size,
+                });
+            }
+        }
+        self.files.sort_by_key(|f| f.pos);
+        blocks
+    }
+
+    // Free space between files, not counting anything after the last one
+    fn free_regions(&self) -> Vec<Gap> {
+        let mut ret = Vec::new();
+        let mut next = 0;
+        for file in self.files.iter().sorted_by_key(|f| f.pos) {
+            if file.pos > next {
+                ret.push(Gap {
+                    pos: next,
+                    size: file.pos - next,
+                });
             }
+            next = next.max(file.pos + file.size);
         }
-        tot
+        ret
+    }
+
+    fn metrics(&self, moved: usize) -> Metrics {
+        let extents = self.files.iter().counts_by(|f| f.id);
+        let end = self.files.iter().map(|f| f.pos + f.size).max().unwrap_or(0);
+        let largest_free = self
+            .free_regions()
+            .iter()
+            .map(|g| g.size)
+            .chain([self.len - end])
+            .max()
+            .unwrap_or(0);
+        Metrics {
+            checksum: self.score1(),
+            moved,
+            mean_extents: self.files.len() as f64 / extents.len() as f64,
+            max_extents: extents.values().copied().max().unwrap_or(0),
+            fragmented_files: extents.values().filter(|n| **n > 1).count(),
+            largest_free,
+        }
+    }
+
+    fn score1(&self) -> i64 {
+        self.files.iter().map(File::checksum).sum()
     }
 
     fn part1(&mut self) -> i64 {
@@ -117,17 +287,382 @@ impl Disk {
     }
 }
 
+struct Metrics {
+    checksum: i64,
+    moved: usize,
+    mean_extents: f64,
+    max_extents: usize,
+    fragmented_files: usize,
+    largest_free: usize,
+}
+
+trait CompactionStrategy {
+    fn name(&self) -> String;
+    // Returns the number of blocks moved
+    fn compact(&self, disk: &mut Disk) -> usize;
+}
+
+// Part 1: move single blocks into the leftmost free space
+struct Blocks;
+
+// Part 2: move whole files into the leftmost gap, using the free-space index
+struct IndexedFirstFit;
+
+#[derive(Debug, Copy, Clone)]
+enum Fit {
+    First,
+    Best,
+    Worst,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Order {
+    // Highest ID first, like the puzzle
+    Id,
+    // Largest file first
+    Size,
+}
+
+// Whole-file moves with a general free list, so gaps can merge as files leave them
+struct WholeFiles {
+    fit: Fit,
+    order: Order,
+    // Also let files move right, if that leaves fewer free regions
+    allow_right: bool,
+}
+
+impl CompactionStrategy for Blocks {
+    fn name(&self) -> String {
+        "blocks".to_owned()
+    }
+    fn compact(&self, disk: &mut Disk) -> usize {
+        disk.compact1()
+    }
+}
+
+impl CompactionStrategy for IndexedFirstFit {
+    fn name(&self) -> String {
+        "indexed first-fit".to_owned()
+    }
+    fn compact(&self, disk: &mut Disk) -> usize {
+        disk.compact2()
+    }
+}
+
+// Free regions by position, with a second index by size for best fit, and a max tree over
+// positions so the largest or leftmost gap before a file is found without scanning
+struct FreeList {
+    by_pos: BTreeMap<usize, usize>,
+    by_size: BTreeSet<(usize, usize)>,
+    // Leaves are gap sizes at each starting position, inner nodes the max of their children
+    tree: Vec<usize>,
+    leaves: usize,
+}
+
+impl FreeList {
+    fn new(len: usize) -> Self {
+        let leaves = (len + 1).next_power_of_two();
+        Self {
+            by_pos: BTreeMap::new(),
+            by_size: BTreeSet::new(),
+            tree: vec![0; 2 * leaves],
+            leaves,
+        }
+    }
+
+    fn set(&mut self, pos: usize, size: usize) {
+        let mut i = pos + self.leaves;
+        self.tree[i] = size;
+        while i > 1 {
+            i /= 2;
+            self.tree[i] = self.tree[2 * i].max(self.tree[2 * i + 1]);
+        }
+    }
+
+    fn insert(&mut self, pos: usize, size: usize) {
+        self.by_pos.insert(pos, size);
+        self.by_size.insert((size, pos));
+        self.set(pos, size);
+    }
+
+    fn remove(&mut self, pos: usize) -> Option<usize> {
+        let size = self.by_pos.remove(&pos)?;
+        self.by_size.remove(&(size, pos));
+        self.set(pos, 0);
+        Some(size)
+    }
+
+    // Mark a region free, merging with its neighbours
+    fn release(&mut self, mut pos: usize, mut size: usize) {
+        if let Some((&p, &s)) = self.by_pos.range(..pos).next_back() {
+            if p + s == pos {
+                self.remove(p);
+                pos = p;
+                size += s;
+            }
+        }
+        if let Some(s) = self.remove(pos + size) {
+            size += s;
+        }
+        self.insert(pos, size);
+    }
+
+    // Largest gap starting before end
+    fn max_before(&self, end: usize) -> usize {
+        let (mut lo, mut hi) = (self.leaves, end + self.leaves);
+        let mut ret = 0;
+        while lo < hi {
+            if lo % 2 == 1 {
+                ret = ret.max(self.tree[lo]);
+                lo += 1;
+            }
+            if hi % 2 == 1 {
+                hi -= 1;
+                ret = ret.max(self.tree[hi]);
+            }
+            lo /= 2;
+            hi /= 2;
+        }
+        ret
+    }
+
+    // Leftmost gap starting before end with at least the given size
+    fn leftmost_before(&self, end: usize, size: usize) -> Option<Gap> {
+        if size == 0 || self.max_before(end) < size {
+            return None;
+        }
+        let mut i = 1;
+        while i < self.leaves {
+            i = if self.tree[2 * i] >= size { 2 * i } else { 2 * i + 1 };
+        }
+        let pos = i - self.leaves;
+        Some(Gap {
+            pos,
+            size: self.by_pos[&pos],
+        })
+    }
+}
+
+impl WholeFiles {
+    // Change in the number of free regions from moving file into gap
+    fn fragmentation_delta(free: &FreeList, file: &File, gap: &Gap) -> i64 {
+        let rest = (gap.size > file.size).then(|| Gap {
+            pos: gap.pos + file.size,
+            size: gap.size - file.size,
+        });
+        let (start, end) = (file.pos, file.pos + file.size);
+        let free_before = free
+            .by_pos
+            .range(..start)
+            .next_back()
+            .is_some_and(|(p, s)| p + s == start && *p != gap.pos)
+            || rest.as_ref().is_some_and(|r| r.pos + r.size == start);
+        let free_after = (free.by_pos.contains_key(&end) && end != gap.pos)
+            || rest.as_ref().is_some_and(|r| r.pos == end);
+        let filled = if rest.is_some() { 0 } else { -1 };
+        filled + 1 - free_before as i64 - free_after as i64
+    }
+
+    // Free space on each side of the file. Moving right can only reduce the free regions if the
+    // space it leaves merges with one of those: with one, only into a gap it fills exactly, and
+    // with both, into any gap.
+    fn free_sides(free: &FreeList, file: &File) -> usize {
+        let start = file.pos;
+        let before = free
+            .by_pos
+            .range(..start)
+            .next_back()
+            .is_some_and(|(p, s)| p + s == start);
+        let after = free.by_pos.contains_key(&(file.pos + file.size));
+        before as usize + after as usize
+    }
+
+    // Gaps of one size that are allowed, leftmost first. The leftmost is the only one that can
+    // be left of the file, so the rest only need looking at when moving right is possible.
+    fn allowed_of_size(free: &FreeList, file: &File, size: usize, sides: usize) -> Option<Gap> {
+        let mut gaps = free
+            .by_size
+            .range((size, 0)..=(size, usize::MAX))
+            .map(|&(size, pos)| Gap { pos, size });
+        let first = gaps.next()?;
+        if first.pos < file.pos {
+            Some(first)
+        } else if sides == 2 || (sides == 1 && size == file.size) {
+            std::iter::once(first)
+                .chain(gaps)
+                .find(|g| Self::fragmentation_delta(free, file, g) < 0)
+        } else {
+            None
+        }
+    }
+
+    // First and worst fit look left of the file in the max tree. Best fit, and anything moving
+    // right, go through the distinct gap sizes from one end of the size index.
+    fn choose(&self, free: &FreeList, file: &File) -> Option<Gap> {
+        let sides = if self.allow_right {
+            Self::free_sides(free, file)
+        } else {
+            0
+        };
+        match self.fit {
+            Fit::First => {
+                let left = free.leftmost_before(file.pos, file.size);
+                if left.is_some() || sides == 0 {
+                    return left;
+                }
+                let right_of = |g: &Gap| g.pos > file.pos;
+                let candidates: Box<dyn Iterator<Item = Gap>> = if sides == 2 {
+                    Box::new(
+                        free.by_pos
+                            .range(file.pos..)
+                            .map(|(&pos, &size)| Gap { pos, size })
+                            .filter(|g| g.size >= file.size),
+                    )
+                } else {
+                    Box::new(
+                        free.by_size
+                            .range((file.size, file.pos)..=(file.size, usize::MAX))
+                            .map(|&(size, pos)| Gap { pos, size }),
+                    )
+                };
+                candidates
+                    .filter(right_of)
+                    .find(|g| Self::fragmentation_delta(free, file, g) < 0)
+            }
+            Fit::Best => {
+                let largest_left = free.max_before(file.pos);
+                let mut next = free.by_size.range((file.size, 0)..).next();
+                while let Some(&(size, _)) = next {
+                    let right_only = size > largest_left;
+                    if right_only && (sides == 0 || (sides == 1 && size > file.size)) {
+                        break;
+                    }
+                    if let Some(gap) = Self::allowed_of_size(free, file, size, sides) {
+                        return Some(gap);
+                    }
+                    next = free.by_size.range((size + 1, 0)..).next();
+                }
+                None
+            }
+            Fit::Worst => {
+                // Nothing left of the file is bigger than this, so only gaps to the right can be
+                let largest_left = free.max_before(file.pos);
+                let left = free.leftmost_before(file.pos, largest_left.max(file.size));
+                if sides == 0 {
+                    return left;
+                }
+                let mut next = free.by_size.iter().next_back();
+                while let Some(&(size, _)) = next.filter(|(size, _)| *size >= file.size) {
+                    if size <= largest_left {
+                        return left;
+                    }
+                    if let Some(gap) = Self::allowed_of_size(free, file, size, sides) {
+                        return Some(gap);
+                    }
+                    next = free.by_size.range(..(size, 0)).next_back();
+                }
+                left
+            }
+        }
+    }
+}
+
+impl CompactionStrategy for WholeFiles {
+    fn name(&self) -> String {
+        let name = format!("{:?}-fit by {:?}", self.fit, self.order).to_lowercase();
+        if self.allow_right {
+            name + ", moving right"
+        } else {
+            name
+        }
+    }
+
+    fn compact(&self, disk: &mut Disk) -> usize {
+        let mut free = FreeList::new(disk.len);
+        for g in disk.free_regions() {
+            free.insert(g.pos, g.size);
+        }
+        let mut order: Vec<usize> = (0..disk.files.len()).collect();
+        match self.order {
+            Order::Id => order.sort_by_key(|i| Reverse(disk.files[*i].id)),
+            Order::Size => {
+                order.sort_by_key(|i| (Reverse(disk.files[*i].size), Reverse(disk.files[*i].id)))
+            }
+        }
+
+        let mut moved = 0;
+        for i in order {
+            let file = &mut disk.files[i];
+            if let Some(gap) = self.choose(&free, file) {
+                free.remove(gap.pos);
+                if gap.size > file.size {
+                    free.insert(gap.pos + file.size, gap.size - file.size);
+                }
+                free.release(file.pos, file.size);
+                file.pos = gap.pos;
+                moved += file.size;
+            }
+        }
+        disk.files.sort_by_key(|f| f.pos);
+        moved
+    }
+}
+
+fn strategies() -> Vec<Box<dyn CompactionStrategy>> {
+    let mut ret: Vec<Box<dyn CompactionStrategy>> =
+        vec![Box::new(Blocks), Box::new(IndexedFirstFit)];
+    for order in [Order::Id, Order::Size] {
+        for fit in [Fit::First, Fit::Best, Fit::Worst] {
+            for allow_right in [false, true] {
+                ret.push(Box::new(WholeFiles {
+                    fit,
+                    order,
+                    allow_right,
+                }));
+            }
+        }
+    }
+    ret
+}
+
+fn compare(disk: &Disk) {
+    println!(
+        "{:<32} {:>16} {:>8} {:>6} {:>4} {:>10} {:>8} {:>6}",
+        "strategy", "checksum", "moved", "mean", "max", "fragmented", "free", "check"
+    );
+    for strategy in strategies() {
+        let mut compacted = disk.clone();
+        let moved = strategy.compact(&mut compacted);
+        let m = compacted.metrics(moved);
+        let check = match compacted.check(disk) {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => format!("FAILED: {}", e),
+        };
+        println!(
+            "{:<32} {:>16} {:>8} {:>6.3} {:>4} {:>10} {:>8} {:>6}",
+            strategy.name(),
+            m.checksum,
+            m.moved,
+            m.mean_extents,
+            m.max_extents,
+            m.fragmented_files,
+            m.largest_free,
+            check
+        );
+    }
+}
+
 impl fmt::Display for Disk {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for e in self.bitmap.iter() {
-            let c = e.map_or(".".to_owned(), |i| {
-                let mut s = i.to_string();
-                if s.len() > 1 {
-                    s = "{".to_owned() + &s + "}";
-                }
-                s
-            });
-            write!(f, "{}", c)?;
+        let mut next = 0;
+        for file in &self.files {
+            write!(f, "{}", ".".repeat(file.pos - next))?;
+            let mut s = file.id.to_string();
+            if s.len() > 1 {
+                s = "{".to_owned() + &s + "}";
+            }
+            write!(f, "{}", s.repeat(file.size))?;
+            next = file.pos + file.size;
         }
         Ok(())
     }
@@ -135,7 +670,23 @@ impl fmt::Display for Disk {
 
 fn main() {
     let fname = args().nth(1).unwrap();
-    let disk = Disk::parse(&fname);
+    let disk = Disk::parse(&fname).unwrap();
+    match args().nth(2).as_deref() {
+        Some("compare") => {
+            compare(&disk);
+            return;
+        }
+        Some("write") => {
+            let name = args().nth(3).unwrap();
+            let strategy = strategies().into_iter().find(|s| s.name() == name).unwrap();
+            let mut compacted = disk.clone();
+            strategy.compact(&mut compacted);
+            compacted.check(&disk).unwrap();
+            println!("{}", compacted.to_map().0);
+            return;
+        }
+        _ => {}
+    }
     println!("Part 1: {}", disk.clone().part1());
     println!("Part 2: {}", disk.clone().part2());
 }
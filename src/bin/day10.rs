@@ -1,3 +1,4 @@
+use image::{GrayImage, Luma};
 use std::collections::{HashMap, HashSet};
 use std::env::args;
 use std::fs::read_to_string;
@@ -13,38 +14,98 @@ impl Pos {
         Self { x, y }
     }
 
-    fn adjacent(&self) -> Vec<Pos> {
-        vec![
+    fn adjacent(&self, diagonal: bool) -> Vec<Pos> {
+        let mut ret = vec![
             Pos::new(self.x, self.y - 1),
             Pos::new(self.x, self.y + 1),
             Pos::new(self.x - 1, self.y),
             Pos::new(self.x + 1, self.y),
-        ]
+        ];
+        if diagonal {
+            ret.extend([
+                Pos::new(self.x - 1, self.y - 1),
+                Pos::new(self.x + 1, self.y - 1),
+                Pos::new(self.x - 1, self.y + 1),
+                Pos::new(self.x + 1, self.y + 1),
+            ]);
+        }
+        ret
     }
 }
 
+// Impassable cells like '.' are just missing
 type Topo = HashMap<Pos, i64>;
 
+#[derive(Debug, Clone)]
+struct TrailRules {
+    start: i64,
+    end: i64,
+    // Allowed changes in height for one step. All must be ascents, so trails can't loop.
+    steps: Vec<i64>,
+    diagonal: bool,
+}
+
+impl Default for TrailRules {
+    fn default() -> Self {
+        Self {
+            start: 0,
+            end: 9,
+            steps: vec![1],
+            diagonal: false,
+        }
+    }
+}
+
+impl TrailRules {
+    // From args like "start=0 end=9 steps=1,2 diagonal=true", or "ascent=3" for any step up to 3
+    fn parse<'a>(specs: impl Iterator<Item = &'a str>) -> Self {
+        let mut rules = Self::default();
+        for spec in specs {
+            let (key, val) = spec.split_once('=').unwrap();
+            match key {
+                "start" => rules.start = val.parse().unwrap(),
+                "end" => rules.end = val.parse().unwrap(),
+                "steps" => rules.steps = val.split(',').map(|s| s.parse().unwrap()).collect(),
+                "ascent" => rules.steps = (1..=val.parse().unwrap()).collect(),
+                "diagonal" => rules.diagonal = val.parse().unwrap(),
+                _ => panic!("Unknown rule {}", key),
+            }
+        }
+        assert!(rules.steps.iter().all(|s| *s > 0), "Steps must be ascents");
+        rules
+    }
+
+    // Cells a trail can go to next from p
+    fn next<'a>(&'a self, topo: &'a Topo, p: &Pos) -> impl Iterator<Item = Pos> + 'a {
+        let h = topo[p];
+        p.adjacent(self.diagonal).into_iter().filter(move |adj| {
+            topo.get(adj)
+                .is_some_and(|ah| *ah <= self.end && self.steps.contains(&(ah - h)))
+        })
+    }
+}
+
 fn parse(fname: &str) -> Topo {
     let contents = read_to_string(fname).unwrap();
     let mut topo = Topo::new();
     for (y, line) in contents.lines().enumerate() {
         for (x, c) in line.chars().enumerate() {
-            let h = c.to_digit(10).unwrap();
-            topo.insert(Pos::new(x as i64, y as i64), h as i64);
+            if let Some(h) = c.to_digit(10) {
+                topo.insert(Pos::new(x as i64, y as i64), h as i64);
+            }
         }
     }
     topo
 }
 
-fn trailheads(topo: &Topo) -> Vec<Pos> {
+fn trailheads(topo: &Topo, rules: &TrailRules) -> Vec<Pos> {
     topo.iter()
-        .filter(|(_, h)| **h == 0)
+        .filter(|(_, h)| **h == rules.start)
         .map(|(p, _)| *p)
         .collect()
 }
 
-fn reachable_tops(topo: &Topo, trailhead: &Pos) -> HashSet<Pos> {
+fn reachable_tops(topo: &Topo, rules: &TrailRules, trailhead: &Pos) -> HashSet<Pos> {
     let mut tops = HashSet::new();
     let mut done = HashSet::new();
     let mut todo: Vec<Pos> = Vec::new();
@@ -53,55 +114,197 @@ fn reachable_tops(topo: &Topo, trailhead: &Pos) -> HashSet<Pos> {
         if done.contains(&p) {
             continue;
         }
-        if let Some(h) = topo.get(&p) {
-            if *h == 9 {
-                tops.insert(p);
-            }
-            for adj in p.adjacent() {
-                if topo.get(&adj) == Some(&(h + 1)) {
-                    todo.push(adj);
-                }
-            }
+        if topo[&p] == rules.end {
+            tops.insert(p);
+        } else {
+            todo.extend(rules.next(topo, &p));
         }
         done.insert(p);
     }
     tops
 }
 
-fn rating(topo: &Topo, trailhead: &Pos) -> i64 {
-    let mut cur = HashMap::new();
-    cur.insert(*trailhead, 1);
-    let mut next = HashMap::new();
+fn rating_from(topo: &Topo, rules: &TrailRules, p: &Pos, cache: &mut HashMap<Pos, i64>) -> i64 {
+    if topo[p] == rules.end {
+        return 1;
+    }
+    if let Some(ways) = cache.get(p) {
+        return *ways;
+    }
+    let ways = rules
+        .next(topo, p)
+        .map(|adj| rating_from(topo, rules, &adj, cache))
+        .sum();
+    cache.insert(*p, ways);
+    ways
+}
+
+fn rating(topo: &Topo, rules: &TrailRules, trailhead: &Pos) -> i64 {
+    rating_from(topo, rules, trailhead, &mut HashMap::new())
+}
 
-    for level in 0..9 {
-        for (p, ways) in cur.iter() {
-            for adj in p.adjacent() {
-                if topo.get(&adj) == Some(&(level + 1)) {
-                    *next.entry(adj).or_insert(0) += ways;
-                }
+// Depth-first walk over every distinct trail from a trailhead, yielding each one as it's found
+struct Trails<'a> {
+    topo: &'a Topo,
+    rules: &'a TrailRules,
+    todo: Vec<Vec<Pos>>,
+}
+
+impl Iterator for Trails<'_> {
+    type Item = Vec<Pos>;
+
+    fn next(&mut self) -> Option<Vec<Pos>> {
+        while let Some(trail) = self.todo.pop() {
+            let p = trail.last().unwrap();
+            if self.topo[p] == self.rules.end {
+                return Some(trail);
+            }
+            for adj in self.rules.next(self.topo, p) {
+                let mut next = trail.clone();
+                next.push(adj);
+                self.todo.push(next);
             }
         }
-        cur.clone_from(&next);
-        next.clear();
+        None
     }
+}
 
-    cur.values().sum()
+fn trails<'a>(topo: &'a Topo, rules: &'a TrailRules, trailhead: &Pos) -> Trails<'a> {
+    Trails {
+        topo,
+        rules,
+        todo: vec![vec![*trailhead]],
+    }
 }
 
-fn part1(topo: &Topo) -> i64 {
-    trailheads(topo)
+#[derive(Debug)]
+struct TrailheadStats {
+    trailhead: Pos,
+    score: usize,
+    rating: i64,
+    // Most steps in any one trail
+    longest: usize,
+}
+
+fn stats(topo: &Topo, rules: &TrailRules, trailhead: &Pos) -> TrailheadStats {
+    TrailheadStats {
+        trailhead: *trailhead,
+        score: reachable_tops(topo, rules, trailhead).len(),
+        rating: rating(topo, rules, trailhead),
+        longest: trails(topo, rules, trailhead)
+            .map(|t| t.len() - 1)
+            .max()
+            .unwrap_or(0),
+    }
+}
+
+// How many trails pass through each cell
+fn trail_counts(topo: &Topo, rules: &TrailRules) -> HashMap<Pos, usize> {
+    let mut counts = HashMap::new();
+    for th in trailheads(topo, rules) {
+        for trail in trails(topo, rules, &th) {
+            for p in trail {
+                *counts.entry(p).or_default() += 1;
+            }
+        }
+    }
+    counts
+}
+
+fn size(topo: &Topo) -> (i64, i64) {
+    let width = topo.keys().map(|p| p.x).max().unwrap_or(0) + 1;
+    let height = topo.keys().map(|p| p.y).max().unwrap_or(0) + 1;
+    (width, height)
+}
+
+fn print_network(topo: &Topo, rules: &TrailRules) {
+    let counts = trail_counts(topo, rules);
+    let (width, height) = size(topo);
+    for y in 0..height {
+        for x in 0..width {
+            let c = match counts.get(&Pos::new(x, y)) {
+                None => '.',
+                Some(n) if *n < 10 => char::from_digit(*n as u32, 10).unwrap(),
+                Some(_) => '+',
+            };
+            print!("{}", c);
+        }
+        println!();
+    }
+}
+
+fn save_network(topo: &Topo, rules: &TrailRules, fname: &str) {
+    const SCALE: u32 = 4;
+    let counts = trail_counts(topo, rules);
+    let (width, height) = size(topo);
+    let max = counts.values().copied().max().unwrap_or(1) as f64;
+    let mut img = GrayImage::new(width as u32 * SCALE, height as u32 * SCALE);
+    for (p, n) in counts {
+        // Log scale, since a few cells near popular summits get most of the traffic
+        let shade = Luma([(55.0 + 200.0 * (n as f64).ln_1p() / max.ln_1p()) as u8]);
+        for dy in 0..SCALE {
+            for dx in 0..SCALE {
+                img.put_pixel(p.x as u32 * SCALE + dx, p.y as u32 * SCALE + dy, shade);
+            }
+        }
+    }
+    img.save(fname).unwrap();
+}
+
+fn part1(topo: &Topo, rules: &TrailRules) -> i64 {
+    trailheads(topo, rules)
         .iter()
-        .map(|th| reachable_tops(topo, th).len() as i64)
+        .map(|th| reachable_tops(topo, rules, th).len() as i64)
         .sum()
 }
 
-fn part2(topo: &Topo) -> i64 {
-    trailheads(topo).iter().map(|th| rating(topo, th)).sum()
+fn part2(topo: &Topo, rules: &TrailRules) -> i64 {
+    trailheads(topo, rules)
+        .iter()
+        .map(|th| rating(topo, rules, th))
+        .sum()
 }
 
 fn main() {
     let fname = args().nth(1).unwrap();
     let topo = parse(&fname);
-    println!("Part 1: {}", part1(&topo));
-    println!("Part 2: {}", part2(&topo));
+
+    // Anything with an '=' is a rule, the rest say what to do
+    let rest: Vec<String> = args().skip(2).collect();
+    let (specs, mode): (Vec<&String>, Vec<&String>) = rest.iter().partition(|a| a.contains('='));
+    let rules = TrailRules::parse(specs.into_iter().map(|s| s.as_str()));
+
+    match mode.first().map(|s| s.as_str()) {
+        Some("trails") => {
+            for th in trailheads(&topo, &rules) {
+                for trail in trails(&topo, &rules, &th) {
+                    let steps: Vec<String> =
+                        trail.iter().map(|p| format!("{},{}", p.x, p.y)).collect();
+                    println!("{}", steps.join(" "));
+                }
+            }
+        }
+        Some("stats") => {
+            let mut all: Vec<_> = trailheads(&topo, &rules)
+                .iter()
+                .map(|th| stats(&topo, &rules, th))
+                .collect();
+            all.sort_by_key(|s| (s.trailhead.y, s.trailhead.x));
+            println!("trailhead  score  rating  longest");
+            for s in all {
+                let th = format!("{},{}", s.trailhead.x, s.trailhead.y);
+                println!(
+                    "{:>9}  {:>5}  {:>6}  {:>7}",
+                    th, s.score, s.rating, s.longest
+                );
+            }
+        }
+        Some("render") => match mode.get(1) {
+            Some(out) => save_network(&topo, &rules, out),
+            None => print_network(&topo, &rules),
+        },
+        _ => {}
+    }
+    println!("Part 1: {}", part1(&topo, &rules));
+    println!("Part 2: {}", part2(&topo, &rules));
 }
@@ -1,8 +1,15 @@
-use std::collections::HashMap;
+use anyhow::{anyhow, bail, Result};
+use itertools::Itertools;
+use num_bigint::{BigInt, BigUint};
+use std::collections::{BTreeMap, HashMap};
 use std::env::args;
 use std::fs::read_to_string;
 
-type Stones = Vec<i64>;
+// Values can outgrow i64 with rules that only ever multiply, and counts soon outgrow u128, so
+// counting falls back to big integers when the i64/u64 path overflows
+type Val = BigInt;
+type Count = BigUint;
+type Stones = Vec<Val>;
 
 fn parse(fname: &str) -> Stones {
     let input = read_to_string(fname).unwrap();
@@ -12,65 +19,379 @@ fn parse(fname: &str) -> Stones {
         .collect()
 }
 
-fn digits(n: i64) -> u32 {
-    n.checked_ilog10().unwrap_or(0) + 1
+fn digits(n: &Val) -> u32 {
+    n.magnitude().to_str_radix(10).len() as u32
 }
 
-fn next_val(st: i64) -> Vec<i64> {
-    if st == 0 {
-        vec![1]
+fn digits_small(n: i64) -> u32 {
+    n.unsigned_abs().checked_ilog10().unwrap_or(0) + 1
+}
+
+// The puzzle's rules, in the rule DSL
+const PUZZLE_RULES: &str = "
+val == 0 -> 1
+digits even -> left, right
+digits odd, val != 0 -> val * 2024
+";
+
+#[derive(Debug, Clone, Copy)]
+enum Atom {
+    Eq(i64),
+    Ne(i64),
+    // val % m == r, with m positive and r in 0..m
+    Mod(i64, i64),
+    DigitsEven,
+    DigitsOdd,
+}
+
+impl Atom {
+    fn parse(s: &str) -> Result<Self> {
+        let words = s.split_whitespace().collect_vec();
+        let num = |w: &str| w.parse::<i64>().map_err(|_| anyhow!("bad number {:?}", w));
+        Ok(match words[..] {
+            ["val", "==", n] => Self::Eq(num(n)?),
+            ["val", "!=", n] => Self::Ne(num(n)?),
+            ["val", "%", m, "==", r] => {
+                let m = num(m)?;
+                if m <= 0 {
+                    bail!("modulus must be positive in {:?}", s);
+                }
+                // Residues are always taken in 0..m, so -1 means the same as m - 1
+                Self::Mod(m, num(r)?.rem_euclid(m))
+            }
+            ["digits", "even"] => Self::DigitsEven,
+            ["digits", "odd"] => Self::DigitsOdd,
+            _ => bail!("bad condition {:?}", s),
+        })
+    }
+
+    fn matches(&self, val: &Val) -> bool {
+        match self {
+            Self::Eq(n) => *val == Val::from(*n),
+            Self::Ne(n) => *val != Val::from(*n),
+            Self::Mod(m, r) => ((val % m) + m) % m == Val::from(*r),
+            Self::DigitsEven => digits(val).is_multiple_of(2),
+            Self::DigitsOdd => !digits(val).is_multiple_of(2),
+        }
+    }
+
+    fn matches_small(&self, val: i64) -> bool {
+        match self {
+            Self::Eq(n) => val == *n,
+            Self::Ne(n) => val != *n,
+            Self::Mod(m, r) => val.rem_euclid(*m) == *r,
+            Self::DigitsEven => digits_small(val).is_multiple_of(2),
+            Self::DigitsOdd => !digits_small(val).is_multiple_of(2),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Term {
+    Lit(i64),
+    Val,
+    // The two halves of the digits
+    Left,
+    Right,
+    Mul(i64),
+    Add(i64),
+}
+
+impl Term {
+    fn parse(s: &str) -> Result<Self> {
+        let words = s.split_whitespace().collect_vec();
+        let num = |w: &str| w.parse::<i64>().map_err(|_| anyhow!("bad number {:?}", w));
+        Ok(match words[..] {
+            ["val"] => Self::Val,
+            ["left"] => Self::Left,
+            ["right"] => Self::Right,
+            ["val", "*", n] => Self::Mul(num(n)?),
+            ["val", "+", n] => Self::Add(num(n)?),
+            [n] => Self::Lit(num(n)?),
+            _ => bail!("bad result {:?}", s),
+        })
+    }
+
+    fn apply(&self, val: &Val) -> Val {
+        let half = || Val::from(10).pow(digits(val) / 2);
+        match self {
+            Self::Lit(n) => Val::from(*n),
+            Self::Val => val.clone(),
+            Self::Left => val / half(),
+            Self::Right => val % half(),
+            Self::Mul(n) => val * n,
+            Self::Add(n) => val + n,
+        }
+    }
+
+    // None if the result doesn't fit in an i64
+    fn apply_small(&self, val: i64) -> Option<i64> {
+        let half = || 10_i64.pow(digits_small(val) / 2);
+        match self {
+            Self::Lit(n) => Some(*n),
+            Self::Val => Some(val),
+            Self::Left => Some(val / half()),
+            Self::Right => Some(val % half()),
+            Self::Mul(n) => val.checked_mul(*n),
+            Self::Add(n) => val.checked_add(*n),
+        }
+    }
+}
+
+// One line like "digits even -> left, right": every condition must hold, and the stone is
+// replaced by each of the results. No results at all means the stone disappears.
+#[derive(Debug, Clone)]
+struct Rule {
+    line: String,
+    conditions: Vec<Atom>,
+    results: Vec<Term>,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Result<Self> {
+        let (conds, results) = line
+            .split_once("->")
+            .ok_or_else(|| anyhow!("missing '->' in {:?}", line))?;
+        let conditions = match conds.trim() {
+            "any" => Vec::new(),
+            c => c.split(',').map(Atom::parse).try_collect()?,
+        };
+        let results = results
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(Term::parse)
+            .try_collect()?;
+        Ok(Self {
+            line: line.to_owned(),
+            conditions,
+            results,
+        })
+    }
+
+    fn matches(&self, val: &Val) -> bool {
+        self.conditions.iter().all(|a| a.matches(val))
+    }
+
+    // Find a value matching both rules, if any. With no == condition, the conditions are
+    // periodic in val apart from digit parity and finitely many != exclusions, so checking a run
+    // of 6-digit and 7-digit numbers that covers every residue enough times is exhaustive.
+    fn overlap(&self, other: &Rule) -> Result<Option<i64>> {
+        let all = self
+            .conditions
+            .iter()
+            .chain(&other.conditions)
+            .collect_vec();
+        let matches = |v: i64| all.iter().all(|a| a.matches(&Val::from(v)));
+
+        let fixed = all.iter().find_map(|a| match a {
+            Atom::Eq(n) => Some(*n),
+            _ => None,
+        });
+        if let Some(n) = fixed {
+            return Ok(matches(n).then_some(n));
+        }
+
+        let period = all.iter().fold(1, |p, a| match a {
+            Atom::Mod(m, _) => lcm(p, *m),
+            _ => p,
+        });
+        let excluded = all.iter().filter(|a| matches!(a, Atom::Ne(_))).count() as i64;
+        let span = period * (excluded + 1);
+        if span > 800_000 {
+            bail!(
+                "conditions too complex to check: {:?} and {:?}",
+                self.line,
+                other.line
+            );
+        }
+        Ok((0..100)
+            .chain(100_000..100_000 + span)
+            .chain(1_000_000..1_000_000 + span)
+            .find(|v| matches(*v)))
+    }
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a.abs()
     } else {
-        let digits = digits(st);
-        if digits % 2 == 0 {
-            vec![st / 10_i64.pow(digits / 2), st % 10_i64.pow(digits / 2)]
-        } else {
-            vec![st * 2024]
+        gcd(b, a % b)
+    }
+}
+
+fn lcm(a: i64, b: i64) -> i64 {
+    a / gcd(a, b) * b
+}
+
+#[derive(Debug, Clone)]
+struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    // One rule per line, '#' starts a comment
+    fn parse(text: &str) -> Result<Self> {
+        let rules = text
+            .lines()
+            .map(|l| l.split('#').next().unwrap().trim())
+            .filter(|l| !l.is_empty())
+            .map(Rule::parse)
+            .try_collect()?;
+        let ret = Self { rules };
+        ret.validate()?;
+        Ok(ret)
+    }
+
+    // At most one rule may apply to any stone
+    fn validate(&self) -> Result<()> {
+        for (a, b) in self.rules.iter().tuple_combinations() {
+            if let Some(v) = a.overlap(b)? {
+                bail!("rules {:?} and {:?} both match {}", a.line, b.line, v);
+            }
         }
+        Ok(())
+    }
+
+    fn next_val(&self, st: &Val) -> Vec<Val> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|r| r.matches(st))
+            .unwrap_or_else(|| panic!("No rule matches {}", st));
+        rule.results.iter().map(|t| t.apply(st)).collect()
+    }
+
+    fn next_small(&self, st: i64) -> Option<Vec<i64>> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|r| r.conditions.iter().all(|a| a.matches_small(st)))
+            .unwrap_or_else(|| panic!("No rule matches {}", st));
+        rule.results.iter().map(|t| t.apply_small(st)).collect()
+    }
+}
+
+// Tries i64 values and u64 counts first, which is plenty for the puzzle and much quicker
+fn count_n(rules: &RuleSet, stones: &Stones, n: usize) -> Count {
+    let small: Option<Vec<i64>> = stones.iter().map(|st| i64::try_from(st).ok()).collect();
+    let mut cache = HashMap::new();
+    let total = small.and_then(|small| {
+        small.iter().try_fold(0_u64, |tot, st| {
+            tot.checked_add(cache_count_small(rules, &mut cache, *st, n)?)
+        })
+    });
+    match total {
+        Some(total) => Count::from(total),
+        None => count_n_big(rules, stones, n),
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-struct Key {
+// None if anything overflows
+fn cache_count_small(
+    rules: &RuleSet,
+    cache: &mut HashMap<(i64, usize), u64>,
     val: i64,
     rounds: usize,
+) -> Option<u64> {
+    if rounds == 0 {
+        return Some(1);
+    }
+    if let Some(result) = cache.get(&(val, rounds)) {
+        return Some(*result);
+    }
+    let mut tot: u64 = 0;
+    for n in rules.next_small(val)? {
+        tot = tot.checked_add(cache_count_small(rules, cache, n, rounds - 1)?)?;
+    }
+    cache.insert((val, rounds), tot);
+    Some(tot)
 }
 
-impl Key {
-    fn new(val: i64, rounds: usize) -> Self {
-        Self { val, rounds }
+// Distinct stone values with how many of each there are. Order doesn't matter to the count.
+type Multiset = BTreeMap<Val, Count>;
+
+fn multiset(stones: &Stones) -> Multiset {
+    let mut ms = Multiset::new();
+    for st in stones {
+        *ms.entry(st.clone()).or_default() += 1_u32;
     }
+    ms
 }
 
-fn count_n(stones: &Stones, n: usize) -> usize {
-    let mut cache = HashMap::new();
-    stones
-        .iter()
-        .map(|&st| cache_count(&mut cache, &Key::new(st, n)))
-        .sum()
+fn blink(rules: &RuleSet, stones: &Multiset) -> Multiset {
+    let mut ret = Multiset::new();
+    for (val, count) in stones {
+        for n in rules.next_val(val) {
+            *ret.entry(n).or_default() += count;
+        }
+    }
+    ret
 }
 
-fn cache_count(cache: &mut HashMap<Key, usize>, key: &Key) -> usize {
-    if key.rounds == 0 {
-        return 1;
+// Only the number of stones with each value matters, so memory stays flat however many rounds
+fn count_n_big(rules: &RuleSet, stones: &Stones, n: usize) -> Count {
+    let mut ms = multiset(stones);
+    for _ in 0..n {
+        ms = blink(rules, &ms);
     }
+    ms.values().sum()
+}
 
-    if let Some(result) = cache.get(key) {
-        return *result;
+// Show how the multiset evolves over n blinks, and where its set of values starts repeating
+fn evolution(rules: &RuleSet, stones: &Stones, n: usize) {
+    let mut ms = multiset(stones);
+
+    let mut seen: HashMap<Vec<Val>, usize> = HashMap::new();
+    let mut cycle = None;
+    println!("round  distinct  stones");
+    for round in 0..=n {
+        let total: Count = ms.values().sum();
+        println!("{:>5}  {:>8}  {}", round, ms.len(), total);
+        let values = ms.keys().cloned().collect_vec();
+        if cycle.is_none() {
+            if let Some(first) = seen.insert(values, round) {
+                cycle = Some((first, round - first));
+            }
+        }
+        if round < n {
+            ms = blink(rules, &ms);
+        }
     }
 
-    let mut tot = 0;
-    for n in next_val(key.val) {
-        let new_key = Key::new(n, key.rounds - 1);
-        let count = cache_count(cache, &new_key);
-        cache.insert(new_key, count);
-        tot += count;
+    match cycle {
+        Some((first, period)) => println!(
+            "Value set repeats from round {} with period {}",
+            first, period
+        ),
+        None => println!("Value set never repeats"),
+    }
+    println!("Values after {} blinks:", n);
+    for (val, count) in &ms {
+        println!("{} x{}", val, count);
     }
-    tot
 }
 
 fn main() {
     let fname = args().nth(1).unwrap();
     let stones = parse(&fname);
-    println!("Part 1: {:?}", count_n(&stones, 25));
-    println!("Part 1: {:?}", count_n(&stones, 75));
+
+    // Optional rules file, then optional "count N" or "multiset N"
+    let mut rest = args().skip(2).peekable();
+    let rules = match rest.next_if(|a| a != "count" && a != "multiset") {
+        Some(rules_file) => RuleSet::parse(&read_to_string(rules_file).unwrap()),
+        None => RuleSet::parse(PUZZLE_RULES),
+    }
+    .unwrap();
+    let mode = rest.next();
+    let blinks = rest.next().map(|n| n.parse().unwrap());
+
+    match (mode.as_deref(), blinks) {
+        (Some("count"), Some(n)) => println!("{} blinks: {}", n, count_n(&rules, &stones, n)),
+        (Some("multiset"), Some(n)) => evolution(&rules, &stones, n),
+        _ => {
+            println!("Part 1: {}", count_n(&rules, &stones, 25));
+            println!("Part 2: {}", count_n(&rules, &stones, 75));
+        }
+    }
 }
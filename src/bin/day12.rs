@@ -1,5 +1,8 @@
+use itertools::Itertools;
+use std::cmp::Reverse;
 use std::collections::{BTreeSet, HashMap};
 use std::env::args;
+use std::fmt::Write;
 use std::fs::read_to_string;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -18,7 +21,7 @@ impl Pos {
     }
 
     fn adjacent(&self) -> Vec<Pos> {
-        Dir::ALL.iter().map(|d| self.go(&d)).collect()
+        Dir::ALL.iter().map(|d| self.go(d)).collect()
     }
 }
 
@@ -51,44 +54,243 @@ fn parse(fname: &str) -> Garden {
     garden
 }
 
-fn region<GroupFn: Fn(&Pos, &Pos) -> bool>(
-    avail: &BTreeSet<Pos>,
-    start: &Pos,
-    gf: &GroupFn,
-) -> BTreeSet<Pos> {
-    let mut result = BTreeSet::new();
-    result.insert(*start);
-    let mut todo = Vec::new();
-    todo.push(*start);
+// Dense grid of region labels, from a union-find over cells
+struct Labels {
+    width: i64,
+    height: i64,
+    labels: Vec<usize>,
+    count: usize,
+}
 
-    while let Some(p) = todo.pop() {
-        for adj in p.adjacent() {
-            if avail.contains(&adj) && !result.contains(&adj) && gf(start, &adj) {
-                result.insert(adj);
-                todo.push(adj);
+impl Labels {
+    fn new(garden: &Garden) -> Self {
+        let width = garden.keys().map(|p| p.x).max().unwrap_or(-1) + 1;
+        let height = garden.keys().map(|p| p.y).max().unwrap_or(-1) + 1;
+        let ix = |x: i64, y: i64| (y * width + x) as usize;
+
+        fn find(parent: &mut [usize], i: usize) -> usize {
+            let mut root = i;
+            while parent[root] != root {
+                root = parent[root];
+            }
+            let mut i = i;
+            while parent[i] != root {
+                (i, parent[i]) = (parent[i], root);
+            }
+            root
+        }
+
+        let mut parent: Vec<usize> = (0..(width * height) as usize).collect();
+        for y in 0..height {
+            for x in 0..width {
+                let plant = garden[&Pos::new(x, y)];
+                for (nx, ny) in [(x - 1, y), (x, y - 1)] {
+                    if nx >= 0 && ny >= 0 && garden[&Pos::new(nx, ny)] == plant {
+                        let (a, b) = (find(&mut parent, ix(x, y)), find(&mut parent, ix(nx, ny)));
+                        parent[a.max(b)] = a.min(b);
+                    }
+                }
+            }
+        }
+
+        // Number regions in order of their first cell
+        let mut ids = HashMap::new();
+        let labels = (0..parent.len())
+            .map(|i| {
+                let root = find(&mut parent, i);
+                let next = ids.len();
+                *ids.entry(root).or_insert(next)
+            })
+            .collect();
+        Self {
+            width,
+            height,
+            labels,
+            count: ids.len(),
+        }
+    }
+
+    // Label at a cell, or None outside the garden
+    fn get(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            None
+        } else {
+            Some(self.labels[(y * self.width + x) as usize])
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RegionStats {
+    id: usize,
+    plant: char,
+    area: i64,
+    perimeter: i64,
+    sides: i64,
+    min: Point,
+    max: Point,
+    holes: i64,
+    // Innermost region with this one inside one of its holes
+    enclosed_by: Option<usize>,
+}
+
+// Everything but enclosure comes from one pass over the grid vertices. Each vertex looks at
+// the 2x2 block of cells around it: a region with one cell there has a convex corner, with three
+// a concave corner, and with two diagonal cells two corners. Those same counts give the Euler
+// number, which for a single 4-connected region is 1 - holes.
+fn region_stats(garden: &Garden, labels: &Labels) -> Vec<RegionStats> {
+    let mut stats: Vec<RegionStats> = (0..labels.count)
+        .map(|id| RegionStats {
+            id,
+            plant: ' ',
+            area: 0,
+            perimeter: 0,
+            sides: 0,
+            min: (i64::MAX, i64::MAX),
+            max: (i64::MIN, i64::MIN),
+            holes: 0,
+            enclosed_by: None,
+        })
+        .collect();
+    let mut quads = vec![(0, 0, 0); labels.count];
+
+    for y in 0..=labels.height {
+        for x in 0..=labels.width {
+            if let Some(id) = labels.get(x, y) {
+                let st = &mut stats[id];
+                st.plant = garden[&Pos::new(x, y)];
+                st.area += 1;
+                st.perimeter += [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]
+                    .iter()
+                    .filter(|(nx, ny)| labels.get(*nx, *ny) != Some(id))
+                    .count() as i64;
+                st.min = (st.min.0.min(x), st.min.1.min(y));
+                st.max = (st.max.0.max(x), st.max.1.max(y));
+            }
+
+            // Cells around vertex (x, y), clockwise from top-left
+            let block = [
+                labels.get(x - 1, y - 1),
+                labels.get(x, y - 1),
+                labels.get(x, y),
+                labels.get(x - 1, y),
+            ];
+            for id in block.iter().flatten().unique() {
+                let here = block.map(|l| l == Some(*id));
+                let q = &mut quads[*id];
+                match here.iter().filter(|h| **h).count() {
+                    1 => q.0 += 1,
+                    3 => q.1 += 1,
+                    2 if here[0] == here[2] => q.2 += 1,
+                    _ => {}
+                }
             }
         }
     }
-    result
+
+    for (st, (q1, q3, qd)) in stats.iter_mut().zip(quads) {
+        st.sides = q1 + q3 + 2 * qd;
+        st.holes = 1 - (q1 - q3 + 2 * qd) / 4;
+    }
+    find_enclosures(labels, &mut stats);
+    stats
 }
 
-fn regions<GroupFn: Fn(&Pos, &Pos) -> bool>(
-    mut avail: BTreeSet<Pos>,
-    gf: &GroupFn,
-) -> Vec<BTreeSet<Pos>> {
-    let mut regions = Vec::new();
-    while !avail.is_empty() {
-        let pos = avail.iter().next().unwrap();
-        let plot = region(&avail, pos, gf);
-        avail.retain(|p| !plot.contains(p));
-        regions.push(plot);
+// A region is in a hole of another when every way out of the garden from it, moving through
+// regions that touch even at a corner, goes through the other one. That makes the other one a
+// cut vertex of the region graph, with the outside as one more vertex, and the innermost such
+// region is the nearest one above it in a depth-first search from the outside that its subtree
+// can't get round.
+fn find_enclosures(labels: &Labels, stats: &mut [RegionStats]) {
+    let outside = labels.count;
+    let mut edges = Vec::new();
+    for y in -1..=labels.height {
+        for x in -1..=labels.width {
+            let a = labels.get(x, y).unwrap_or(outside);
+            for (nx, ny) in [(x + 1, y), (x - 1, y + 1), (x, y + 1), (x + 1, y + 1)] {
+                let b = labels.get(nx, ny).unwrap_or(outside);
+                if a != b {
+                    edges.push((a.min(b), a.max(b)));
+                }
+            }
+        }
+    }
+    edges.sort_unstable();
+    edges.dedup();
+    let mut adjacent = vec![Vec::new(); outside + 1];
+    for (a, b) in edges {
+        adjacent[a].push(b);
+        adjacent[b].push(a);
+    }
+
+    // Iterative Tarjan, recording each region's innermost enclosure once its subtree is done
+    let mut disc = vec![usize::MAX; outside + 1];
+    let mut low = vec![0; outside + 1];
+    let mut parent = vec![outside; outside + 1];
+    let mut order = Vec::new();
+    let mut stack = vec![(outside, 0)];
+    disc[outside] = 0;
+    while let Some((v, i)) = stack.pop() {
+        if let Some(&w) = adjacent[v].get(i) {
+            stack.push((v, i + 1));
+            if disc[w] == usize::MAX {
+                disc[w] = order.len() + 1;
+                low[w] = disc[w];
+                parent[w] = v;
+                order.push(w);
+                stack.push((w, 0));
+            } else if w != parent[v] {
+                low[v] = low[v].min(disc[w]);
+            }
+        } else if v != outside {
+            let p = parent[v];
+            low[p] = low[p].min(low[v]);
+        }
+    }
+
+    // Parents come before their children in discovery order
+    for v in order {
+        let p = parent[v];
+        stats[v].enclosed_by = if p != outside && low[v] >= disc[p] {
+            Some(p)
+        } else if p != outside {
+            stats[p].enclosed_by
+        } else {
+            None
+        };
+    }
+}
+
+fn print_table(stats: &mut [RegionStats], sort: &str) {
+    match sort {
+        "id" => stats.sort_by_key(|s| s.id),
+        "plant" => stats.sort_by_key(|s| (s.plant, s.id)),
+        "area" => stats.sort_by_key(|s| (Reverse(s.area), s.id)),
+        "perimeter" => stats.sort_by_key(|s| (Reverse(s.perimeter), s.id)),
+        "sides" => stats.sort_by_key(|s| (Reverse(s.sides), s.id)),
+        "holes" => stats.sort_by_key(|s| (Reverse(s.holes), s.id)),
+        _ => panic!("Can't sort by {}", sort),
+    }
+    println!("   id plant   area  perim  sides  bounding box       holes  inside");
+    for s in stats.iter() {
+        let bbox = format!("{},{}-{},{}", s.min.0, s.min.1, s.max.0, s.max.1);
+        let inside = s.enclosed_by.map_or("-".to_owned(), |e| e.to_string());
+        println!(
+            "{:>5} {:>5} {:>6} {:>6} {:>6}  {:<17} {:>5}  {:>6}",
+            s.id, s.plant, s.area, s.perimeter, s.sides, bbox, s.holes, inside
+        );
     }
-    regions
 }
 
 fn plots(garden: &Garden) -> Vec<Plot> {
-    let avail: BTreeSet<Pos> = garden.keys().map(|k| *k).collect();
-    regions(avail, &|a, b| garden.get(a) == garden.get(b))
+    let labels = Labels::new(garden);
+    let mut plots = vec![Plot::new(); labels.count];
+    for y in 0..labels.height {
+        for x in 0..labels.width {
+            plots[labels.get(x, y).unwrap()].insert(Pos::new(x, y));
+        }
+    }
+    plots
 }
 
 fn perimeter(plot: &Plot) -> i64 {
@@ -103,44 +305,191 @@ fn perimeter(plot: &Plot) -> i64 {
     tot
 }
 
-fn cost(plot: &Plot) -> i64 {
-    (plot.len() as i64) * perimeter(plot)
+fn part1(stats: &[RegionStats]) -> i64 {
+    stats.iter().map(|s| s.area * s.perimeter).sum()
 }
 
-fn part1(garden: &Garden) -> i64 {
-    let plots = plots(garden);
-    plots.iter().map(|p| cost(p)).sum()
+fn part2(stats: &[RegionStats]) -> i64 {
+    stats.iter().map(|s| s.area * s.sides).sum()
 }
 
-fn sides(plot: &Plot) -> i64 {
-    let mut by_dir: HashMap<Dir, BTreeSet<Pos>> =
-        Dir::ALL.iter().map(|d| (*d, BTreeSet::new())).collect();
-    for start in plot {
-        for dir in Dir::ALL {
-            let target = start.go(&dir);
-            if !plot.contains(&target) {
-                by_dir.get_mut(&dir).unwrap().insert(target);
+type Point = (i64, i64);
+type Ring = Vec<Point>;
+
+// A region's boundary, with the region on the right of each ring as you walk it in screen
+// coordinates. So the outer ring is clockwise on screen, and holes are counter-clockwise.
+#[derive(Debug, Clone)]
+struct Polygon {
+    outer: Ring,
+    holes: Vec<Ring>,
+}
+
+impl Polygon {
+    fn corners(&self) -> usize {
+        self.outer.len() + self.holes.iter().map(|h| h.len()).sum::<usize>()
+    }
+}
+
+// Twice the signed area, positive for the outer ring
+fn signed_area2(ring: &Ring) -> i64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|((x1, y1), (x2, y2))| x1 * y2 - x2 * y1)
+        .sum()
+}
+
+fn trace(plot: &Plot) -> Polygon {
+    // Unit edges along each cell side that faces out of the plot, keyed by start point
+    let mut edges: HashMap<Point, Vec<Point>> = HashMap::new();
+    for p in plot {
+        let (x, y) = (p.x, p.y);
+        for (dir, from, to) in [
+            (Dir::ALL[3], (x, y), (x + 1, y)),
+            (Dir::ALL[0], (x + 1, y), (x + 1, y + 1)),
+            (Dir::ALL[1], (x + 1, y + 1), (x, y + 1)),
+            (Dir::ALL[2], (x, y + 1), (x, y)),
+        ] {
+            if !plot.contains(&p.go(&dir)) {
+                edges.entry(from).or_default().push(to);
             }
         }
     }
-    by_dir
-        .values()
-        .map(|dp| regions(dp.clone(), &|_, _| true).len() as i64)
-        .sum()
+
+    let mut rings = Vec::new();
+    while let Some(&start) = edges.keys().next() {
+        let mut ring = vec![start];
+        let mut cur = start;
+        let mut heading = (0, 0);
+        loop {
+            let nexts = edges.get_mut(&cur).unwrap();
+            // Where two cells of the plot touch diagonally, turn right to hug the current cell
+            let turn = |to: &Point| {
+                let d = (to.0 - cur.0, to.1 - cur.1);
+                let cross = heading.0 * d.1 - heading.1 * d.0;
+                -cross.signum()
+            };
+            let ix = (0..nexts.len()).min_by_key(|i| turn(&nexts[*i])).unwrap();
+            let next = nexts.swap_remove(ix);
+            if nexts.is_empty() {
+                edges.remove(&cur);
+            }
+            heading = (next.0 - cur.0, next.1 - cur.1);
+            cur = next;
+            if cur == start {
+                break;
+            }
+            ring.push(cur);
+        }
+        rings.push(simplify(ring));
+    }
+
+    let (outer, holes): (Vec<Ring>, Vec<Ring>) =
+        rings.into_iter().partition(|r| signed_area2(r) > 0);
+    Polygon {
+        outer: outer.into_iter().next().unwrap(),
+        holes,
+    }
+}
+
+// Drop points in the middle of straight runs, leaving just the corners
+fn simplify(ring: Ring) -> Ring {
+    let n = ring.len();
+    (0..n)
+        .filter(|i| {
+            let (a, b, c) = (ring[(i + n - 1) % n], ring[*i], ring[(i + 1) % n]);
+            (b.0 - a.0) * (c.1 - b.1) != (b.1 - a.1) * (c.0 - b.0)
+        })
+        .map(|i| ring[i])
+        .collect()
+}
+
+fn plant_color(plant: char) -> String {
+    let hue = (plant as u32 * 47) % 360;
+    format!("hsl({}, 70%, 55%)", hue)
+}
+
+fn to_svg(garden: &Garden) -> String {
+    const SCALE: i64 = 10;
+    let width = garden.keys().map(|p| p.x).max().unwrap_or(0) + 1;
+    let height = garden.keys().map(|p| p.y).max().unwrap_or(0) + 1;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n",
+        width * SCALE,
+        height * SCALE
+    );
+    for plot in plots(garden) {
+        let plant = garden[plot.first().unwrap()];
+        let poly = trace(&plot);
+        let mut d = String::new();
+        for ring in [&poly.outer].into_iter().chain(&poly.holes) {
+            for (i, (x, y)) in ring.iter().enumerate() {
+                let cmd = if i == 0 { 'M' } else { 'L' };
+                write!(d, "{}{} {} ", cmd, x * SCALE, y * SCALE).unwrap();
+            }
+            d.push_str("Z ");
+        }
+        writeln!(
+            svg,
+            "  <path d=\"{}\" fill=\"{}\" fill-rule=\"evenodd\" stroke=\"black\"><title>{}</title></path>",
+            d.trim_end(),
+            plant_color(plant),
+            plant
+        )
+        .unwrap();
+    }
+    svg.push_str("</svg>\n");
+    svg
 }
 
-fn cost2(plot: &Plot) -> i64 {
-    (plot.len() as i64) * sides(plot)
+// GeoJSON wants y up, and then the outer ring comes out counter-clockwise as it should
+fn geojson_ring(ring: &Ring) -> String {
+    let points = ring
+        .iter()
+        .chain(ring.first())
+        .map(|(x, y)| format!("[{},{}]", x, -y))
+        .collect::<Vec<_>>();
+    format!("[{}]", points.join(","))
 }
 
-fn part2(garden: &Garden) -> i64 {
-    let plots = plots(garden);
-    plots.iter().map(|p| cost2(p)).sum()
+fn to_geojson(garden: &Garden) -> String {
+    let features = plots(garden)
+        .iter()
+        .map(|plot| {
+            let poly = trace(plot);
+            let rings = [&poly.outer]
+                .into_iter()
+                .chain(&poly.holes)
+                .map(geojson_ring)
+                .collect::<Vec<_>>();
+            format!(
+                "{{\"type\":\"Feature\",\"properties\":{{\"plant\":\"{}\",\"area\":{},\"perimeter\":{},\"corners\":{}}},\"geometry\":{{\"type\":\"Polygon\",\"coordinates\":[{}]}}}}",
+                garden[plot.first().unwrap()],
+                plot.len(),
+                perimeter(plot),
+                poly.corners(),
+                rings.join(",")
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "{{\"type\":\"FeatureCollection\",\"features\":[\n{}\n]}}\n",
+        features.join(",\n")
+    )
 }
 
 fn main() {
     let fname = args().nth(1).unwrap();
     let garden = parse(&fname);
-    println!("Part 1: {}", part1(&garden));
-    println!("Part 1: {}", part2(&garden));
+    let stats = region_stats(&garden, &Labels::new(&garden));
+    match args().nth(2).as_deref() {
+        Some("svg") => std::fs::write(args().nth(3).unwrap(), to_svg(&garden)).unwrap(),
+        Some("geojson") => std::fs::write(args().nth(3).unwrap(), to_geojson(&garden)).unwrap(),
+        Some("table") => {
+            let sort = args().nth(3).unwrap_or("id".to_owned());
+            print_table(&mut stats.clone(), &sort);
+        }
+        _ => {}
+    }
+    println!("Part 1: {}", part1(&stats));
+    println!("Part 2: {}", part2(&stats));
 }
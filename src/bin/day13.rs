@@ -1,99 +1,467 @@
+use anyhow::{anyhow, bail, Result};
+use itertools::Itertools;
+use rational::extras::lcm;
 use rational::{self, Rational};
 use regex::Regex;
 use std::env::args;
 use std::fs::read_to_string;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd)]
-struct Coord {
-    x: i64,
-    y: i64,
+#[derive(Debug, Clone)]
+struct Config {
+    // Tokens per press of each button in order. Any without a cost given cost 1.
+    costs: Vec<i64>,
+    // Most presses allowed of each button
+    max_presses: Option<i64>,
 }
 
-impl Coord {
-    fn new(x: i64, y: i64) -> Self {
-        Self { x, y }
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            costs: vec![3, 1],
+            max_presses: None,
+        }
+    }
+}
+
+impl Config {
+    // From args like "cost=3,1,2 max=100"
+    fn parse<'a>(specs: impl Iterator<Item = &'a str>) -> Self {
+        let mut config = Self::default();
+        for spec in specs {
+            let (key, val) = spec.split_once('=').unwrap();
+            match key {
+                "cost" => config.costs = val.split(',').map(|c| c.parse().unwrap()).collect(),
+                "max" => config.max_presses = Some(val.parse().unwrap()),
+                _ => panic!("Unknown setting {}", key),
+            }
+        }
+        assert!(config.costs.iter().all(|c| *c >= 0), "Costs can't be negative");
+        config
+    }
+
+    fn cost(&self, button: usize) -> i64 {
+        self.costs.get(button).copied().unwrap_or(1)
+    }
+
+    fn allows(&self, presses: i128) -> bool {
+        presses >= 0 && self.max_presses.is_none_or(|m| presses <= m as i128)
     }
 }
 
 #[derive(Debug)]
 struct Solution {
-    a: i64,
-    b: i64,
+    presses: Vec<i64>,
 }
 
 impl Solution {
-    fn score(&self) -> i64 {
-        3 * self.a + self.b
+    fn score(&self, config: &Config) -> i64 {
+        self.presses
+            .iter()
+            .enumerate()
+            .map(|(i, n)| config.cost(i) * n)
+            .sum()
+    }
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Solved(Solution),
+    // No combination of presses, even fractional or negative, reaches the prize
+    Inconsistent,
+    // Only ones that aren't whole, or are negative or over the limit
+    NoSolution,
+    // Too many free buttons to search, and why
+    Unbounded(String),
+}
+
+// What elimination says about the system of one equation per axis
+#[derive(Debug)]
+struct Shape {
+    buttons: usize,
+    axes: usize,
+    rank: usize,
+    consistent: bool,
+}
+
+impl Shape {
+    fn describe(&self) -> String {
+        let mut ret = Vec::new();
+        if !self.consistent {
+            ret.push("inconsistent".to_owned());
+        }
+        if self.rank < self.buttons {
+            ret.push(format!(
+                "underdetermined, {} free",
+                self.buttons - self.rank
+            ));
+        }
+        if self.rank < self.axes && self.consistent {
+            ret.push(format!(
+                "overdetermined, {} redundant",
+                self.axes - self.rank
+            ));
+        }
+        if ret.is_empty() {
+            ret.push("determined".to_owned());
+        }
+        ret.join("; ")
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd)]
+fn div_floor(a: i128, b: i128) -> i128 {
+    let q = a / b;
+    if a % b != 0 && (a < 0) != (b < 0) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+fn div_ceil(a: i128, b: i128) -> i128 {
+    -div_floor(-a, b)
+}
+
+// Most combinations of presses of the free buttons to try for one machine
+const MAX_TRIES: u64 = 1_000_000;
+
+// A reduced row echelon form of the equations, with the column of the pivot in each row
+struct Echelon {
+    rows: Vec<Vec<Rational>>,
+    pivots: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct Machine {
-    a: Coord,
-    b: Coord,
-    prize: Coord,
+    names: Vec<String>,
+    axes: Vec<String>,
+    // Movement of each button along each axis
+    buttons: Vec<Vec<i64>>,
+    prize: Vec<i64>,
 }
 
 impl Machine {
-    fn solution(&self) -> Option<Solution> {
-        let ax = Rational::integer(self.a.x as i128);
-        let ay = Rational::integer(self.a.y as i128);
-        let bx = Rational::integer(self.b.x as i128);
-        let by = Rational::integer(self.b.y as i128);
-        let px = Rational::integer(self.prize.x as i128);
-        let py = Rational::integer(self.prize.y as i128);
-
-        let nb = (py * ax - px * ay) / (by * ax - bx * ay);
-        let na = (px - bx * nb) / ax;
-        if nb.is_integer() && na.is_integer() {
-            Some(Solution {
-                a: na.numerator() as i64,
-                b: nb.numerator() as i64,
+    fn echelon(&self) -> Echelon {
+        let n = self.buttons.len();
+        let mut rows: Vec<Vec<Rational>> = (0..self.axes.len())
+            .map(|i| {
+                self.buttons
+                    .iter()
+                    .map(|b| Rational::integer(b[i] as i128))
+                    .chain([Rational::integer(self.prize[i] as i128)])
+                    .collect()
             })
-        } else {
-            None
+            .collect();
+
+        let mut pivots = Vec::new();
+        for col in 0..n {
+            let r = pivots.len();
+            let Some(found) = (r..rows.len()).find(|i| rows[*i][col] != 0) else {
+                continue;
+            };
+            rows.swap(r, found);
+            let lead = rows[r][col];
+            for v in rows[r].iter_mut() {
+                *v /= lead;
+            }
+            let pivot = rows[r].clone();
+            for (i, row) in rows.iter_mut().enumerate() {
+                let factor = row[col];
+                if i != r && factor != 0 {
+                    for (v, pv) in row.iter_mut().zip(&pivot) {
+                        *v -= *pv * factor;
+                    }
+                }
+            }
+            pivots.push(col);
         }
+        Echelon { rows, pivots }
     }
 
-    fn score(&self) -> i64 {
-        self.solution().map(|s| s.score()).unwrap_or(0)
+    fn shape(&self, ech: &Echelon) -> Shape {
+        let n = self.buttons.len();
+        let rank = ech.pivots.len();
+        Shape {
+            buttons: n,
+            axes: self.axes.len(),
+            rank,
+            consistent: ech.rows[rank..].iter().all(|row| row[n] == 0),
+        }
+    }
+
+    // Presses of a button that can't overshoot the prize, from an axis no button goes backwards on
+    fn bound(&self, button: usize, config: &Config) -> Option<i64> {
+        let derived = (0..self.axes.len())
+            .filter(|i| self.buttons.iter().all(|b| b[*i] >= 0) && self.buttons[button][*i] > 0)
+            .map(|i| self.prize[i] / self.buttons[button][i])
+            .min();
+        match (derived, config.max_presses) {
+            (Some(d), Some(m)) => Some(d.min(m)),
+            (d, m) => d.or(m),
+        }
+    }
+
+    // Every solution is the pivot buttons in terms of the free ones. All free buttons but the
+    // last are tried in turn, which leaves each button's presses affine in the last one, t.
+    // Those are all whole for t in one residue class, and the cost is linear in t, so the
+    // cheapest is at one end of the range of t that keeps every button within the limits.
+    fn solve(&self, config: &Config) -> (Shape, Outcome) {
+        let ech = self.echelon();
+        let shape = self.shape(&ech);
+        if !shape.consistent {
+            return (shape, Outcome::Inconsistent);
+        }
+        let n = self.buttons.len();
+        let free = (0..n).filter(|c| !ech.pivots.contains(c)).collect_vec();
+
+        let (last, searched) = match free.split_last() {
+            Some((last, searched)) => (Some(*last), searched),
+            None => (None, &[][..]),
+        };
+        let mut ranges = Vec::new();
+        for &f in searched {
+            match self.bound(f, config) {
+                Some(b) => ranges.push(0..=b),
+                None => {
+                    let why = format!("needs a press limit for button {}", self.names[f]);
+                    return (shape, Outcome::Unbounded(why));
+                }
+            }
+        }
+        let tries = ranges
+            .iter()
+            .try_fold(1_u64, |n, r| n.checked_mul(*r.end() as u64 + 1))
+            .filter(|n| *n <= MAX_TRIES);
+        if tries.is_none() {
+            let names = searched.iter().map(|f| &self.names[*f]).join(", ");
+            let why = format!("too many combinations of free buttons {} to try", names);
+            return (shape, Outcome::Unbounded(why));
+        }
+
+        let best = ranges
+            .into_iter()
+            .multi_cartesian_product()
+            .filter_map(|fixed| self.best_along(&ech, config, searched, &fixed, last))
+            .min_by_key(|s| s.score(config));
+        let outcome = match best {
+            Some(s) => Outcome::Solved(s),
+            None => Outcome::NoSolution,
+        };
+        (shape, outcome)
+    }
+
+    fn best_along(
+        &self,
+        ech: &Echelon,
+        config: &Config,
+        searched: &[usize],
+        fixed: &[i64],
+        last: Option<usize>,
+    ) -> Option<Solution> {
+        let n = self.buttons.len();
+        let zero = Rational::zero();
+
+        // Presses of each button as e + g * t
+        let mut line = vec![(zero, zero); n];
+        for (f, v) in searched.iter().zip(fixed) {
+            line[*f] = (Rational::integer(*v as i128), zero);
+        }
+        if let Some(f) = last {
+            line[f] = (zero, Rational::one());
+        }
+        for (row, p) in ech.rows.iter().zip(&ech.pivots) {
+            let mut e = row[n];
+            let mut g = zero;
+            for (f, v) in searched.iter().zip(fixed) {
+                e -= row[*f] * Rational::integer(*v as i128);
+            }
+            if let Some(f) = last {
+                g -= row[f];
+            }
+            line[*p] = (e, g);
+        }
+
+        // Step through t by the period over which everything comes back to whole numbers
+        let period = line.iter().fold(1, |l, (_, g)| lcm(l, g.denominator()));
+        let start = (0..period).find(|t| {
+            line.iter()
+                .all(|(e, g)| (*e + *g * Rational::integer(*t)).is_integer())
+        })?;
+        let steps = line
+            .iter()
+            .map(|(e, g)| {
+                let n0 = (*e + *g * Rational::integer(start)).numerator();
+                let d = (*g * Rational::integer(period)).numerator();
+                (n0, d)
+            })
+            .collect_vec();
+
+        let mut lo: Option<i128> = None;
+        let mut hi: Option<i128> = None;
+        for &(n0, d) in &steps {
+            if d == 0 {
+                if !config.allows(n0) {
+                    return None;
+                }
+                continue;
+            }
+            let mut limits = vec![(0, d > 0)];
+            if let Some(m) = config.max_presses {
+                limits.push((m as i128, d < 0));
+            }
+            for (limit, lower) in limits {
+                if lower {
+                    let k = div_ceil(limit - n0, d);
+                    lo = Some(lo.map_or(k, |l| l.max(k)));
+                } else {
+                    let k = div_floor(limit - n0, d);
+                    hi = Some(hi.map_or(k, |h| h.min(k)));
+                }
+            }
+        }
+        if let (Some(l), Some(h)) = (lo, hi) {
+            if l > h {
+                return None;
+            }
+        }
+
+        let slope: i128 = steps
+            .iter()
+            .enumerate()
+            .map(|(i, (_, d))| config.cost(i) as i128 * d)
+            .sum();
+        let k = if slope >= 0 { lo.or(hi) } else { hi.or(lo) }.unwrap_or(0);
+        Some(Solution {
+            presses: steps.iter().map(|(n0, d)| (n0 + k * d) as i64).collect(),
+        })
+    }
+
+    fn score(&self, config: &Config) -> i64 {
+        match self.solve(config).1 {
+            Outcome::Solved(s) => s.score(config),
+            _ => 0,
+        }
     }
 
     fn part2(&self) -> Self {
         let mut r = self.clone();
-        r.prize.x += 10000000000000;
-        r.prize.y += 10000000000000;
+        for p in r.prize.iter_mut() {
+            *p += 10000000000000;
+        }
         r
     }
 }
 
-fn parse(fname: &str) -> Vec<Machine> {
-    let re = Regex::new(
-        r"Button A: X\+(\d+), Y\+(\d+)\nButton B: X\+(\d+), Y\+(\d+)\nPrize: X=(\d+), Y=(\d+)",
-    )
-    .unwrap();
-    let contents = read_to_string(fname).unwrap();
+// Blocks of "Button <name>: X+94, Y-34, ..." lines then "Prize: X=8400, Y=5400, ...". The prize
+// says what the axes are, and buttons that don't mention one don't move along it.
+fn parse(fname: &str) -> Result<Vec<Machine>> {
+    let line_re = Regex::new(r"^(?:Button (\w+)|Prize):(.*)$").unwrap();
+    let part_re = Regex::new(r"^([A-Za-z]+)=?([+-]?\d+)$").unwrap();
+    let contents = read_to_string(fname)?;
 
-    re.captures_iter(&contents)
-        .map(|cap| Machine {
-            a: Coord::new(cap[1].parse().unwrap(), cap[2].parse().unwrap()),
-            b: Coord::new(cap[3].parse().unwrap(), cap[4].parse().unwrap()),
-            prize: Coord::new(cap[5].parse().unwrap(), cap[6].parse().unwrap()),
-        })
-        .collect()
+    let mut machines = Vec::new();
+    for block in contents.split("\n\n").filter(|b| !b.trim().is_empty()) {
+        let mut buttons: Vec<(String, Vec<(String, i64)>)> = Vec::new();
+        let mut prize = None;
+        for line in block.lines() {
+            let cap = line_re
+                .captures(line.trim())
+                .ok_or_else(|| anyhow!("bad line {:?}", line))?;
+            let parts: Vec<(String, i64)> = cap[2]
+                .split(',')
+                .map(|p| {
+                    let pc = part_re
+                        .captures(p.trim())
+                        .ok_or_else(|| anyhow!("bad component {:?}", p))?;
+                    Ok::<_, anyhow::Error>((pc[1].to_owned(), pc[2].parse()?))
+                })
+                .try_collect()?;
+            match cap.get(1) {
+                Some(name) => buttons.push((name.as_str().to_owned(), parts)),
+                None if prize.is_none() => prize = Some(parts),
+                None => bail!("two prizes in machine {}", machines.len()),
+            }
+        }
+        let prize = prize.ok_or_else(|| anyhow!("no prize in machine {}", machines.len()))?;
+        let axes = prize.iter().map(|(a, _)| a.clone()).collect_vec();
+        if !axes.iter().all_unique() {
+            bail!("repeated axis in machine {}", machines.len());
+        }
+
+        let mut moves = Vec::new();
+        for (name, parts) in &buttons {
+            let mut v = vec![0; axes.len()];
+            for (axis, n) in parts {
+                let i = axes
+                    .iter()
+                    .position(|a| a == axis)
+                    .ok_or_else(|| anyhow!("button {} moves along unknown axis {}", name, axis))?;
+                v[i] = *n;
+            }
+            moves.push(v);
+        }
+        machines.push(Machine {
+            names: buttons.into_iter().map(|(name, _)| name).collect(),
+            axes,
+            buttons: moves,
+            prize: prize.into_iter().map(|(_, n)| n).collect(),
+        });
+    }
+    Ok(machines)
+}
+
+fn report(machines: &[Machine], config: &Config) {
+    for (i, m) in machines.iter().enumerate() {
+        let (shape, outcome) = m.solve(config);
+        let result = match outcome {
+            Outcome::Solved(s) => {
+                let presses = m
+                    .names
+                    .iter()
+                    .zip(&s.presses)
+                    .map(|(name, n)| format!("{}={}", name, n))
+                    .join(" ");
+                format!("{} for {} tokens", presses, s.score(config))
+            }
+            Outcome::Inconsistent => "prize out of reach".to_owned(),
+            Outcome::NoSolution => "no whole number of presses within limits".to_owned(),
+            Outcome::Unbounded(why) => why,
+        };
+        println!(
+            "{:>4}: {} buttons, {} axes, rank {} ({}): {}",
+            i,
+            shape.buttons,
+            shape.axes,
+            shape.rank,
+            shape.describe(),
+            result
+        );
+    }
 }
 
-fn part1(machines: &Vec<Machine>) -> i64 {
-    machines.iter().map(|m| m.score()).sum()
+fn part1(machines: &[Machine], config: &Config) -> i64 {
+    let config = Config {
+        max_presses: config.max_presses.or(Some(100)),
+        ..config.clone()
+    };
+    machines.iter().map(|m| m.score(&config)).sum()
 }
 
-fn part2(machines: &Vec<Machine>) -> i64 {
-    machines.iter().map(|m| m.part2().score()).sum()
+fn part2(machines: &[Machine], config: &Config) -> i64 {
+    machines.iter().map(|m| m.part2().score(config)).sum()
 }
 
 fn main() {
     let fname = args().nth(1).unwrap();
-    let machines = parse(&fname);
-    println!("Part1: {}", part1(&machines));
-    println!("Part2: {}", part2(&machines));
+    let machines = parse(&fname).unwrap();
+
+    // Anything with an '=' is a setting, the rest say what to do
+    let rest: Vec<String> = args().skip(2).collect();
+    let (specs, mode): (Vec<&String>, Vec<&String>) = rest.iter().partition(|a| a.contains('='));
+    let config = Config::parse(specs.into_iter().map(|s| s.as_str()));
+
+    if mode.first().map(|s| s.as_str()) == Some("report") {
+        report(&machines, &config);
+    }
+    println!("Part1: {}", part1(&machines, &config));
+    println!("Part2: {}", part2(&machines, &config));
 }
@@ -1,7 +1,6 @@
 use image::{GrayImage, Luma};
 use itertools::Itertools;
 use regex::{Captures, Regex};
-use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::env::args;
 use std::fmt::{Display, Formatter};
@@ -34,6 +33,101 @@ struct Robot {
     velocity: Coord2,
 }
 
+// A rectangle of cells, corners included
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Zone {
+    min: Coord2,
+    max: Coord2,
+}
+
+impl Zone {
+    fn contains(&self, p: &Coord2) -> bool {
+        (self.min.x..=self.max.x).contains(&p.x) && (self.min.y..=self.max.y).contains(&p.y)
+    }
+}
+
+// Zones to count robots in. They needn't cover the room, and may overlap.
+#[derive(Debug, Clone)]
+struct Partition {
+    zones: Vec<Zone>,
+}
+
+impl Partition {
+    // Starts of n equal spans along a line of cells, with any left over as gaps between them.
+    // That's how the puzzle's quadrants leave out the middle.
+    fn spans(len: i64, n: i64) -> Vec<(i64, i64)> {
+        let (width, rest) = (len / n, len % n);
+        (0..n)
+            .map(|i| {
+                let start = i * width + if n > 1 { i * rest / (n - 1) } else { 0 };
+                (start, start + width - 1)
+            })
+            .collect()
+    }
+
+    // n columns by m rows of zones, row by row
+    fn grid(size: &Coord2, n: i64, m: i64) -> Self {
+        let zones = Self::spans(size.y, m)
+            .into_iter()
+            .cartesian_product(Self::spans(size.x, n))
+            .map(|((y0, y1), (x0, x1))| Zone {
+                min: Coord2::new(x0, y0),
+                max: Coord2::new(x1, y1),
+            })
+            .collect();
+        Self { zones }
+    }
+
+    fn quadrants(size: &Coord2) -> Self {
+        Self::grid(size, 2, 2)
+    }
+
+    // One zone per line like "0,0 49,50", '#' starts a comment
+    fn load(fname: &str) -> Self {
+        let re = Regex::new(r"^(\d+),(\d+)\s+(\d+),(\d+)$").unwrap();
+        let zones = read_to_string(fname)
+            .unwrap()
+            .lines()
+            .map(|l| l.split('#').next().unwrap().trim())
+            .filter(|l| !l.is_empty())
+            .map(|l| {
+                let caps = re
+                    .captures(l)
+                    .unwrap_or_else(|| panic!("Bad zone {:?}", l));
+                Zone {
+                    min: Coord2::from_caps(&caps, 1),
+                    max: Coord2::from_caps(&caps, 3),
+                }
+            })
+            .collect();
+        Self { zones }
+    }
+
+    // "3x2" for a grid of zones, anything else is a file of them
+    fn from_spec(spec: &str, size: &Coord2) -> Self {
+        let re = Regex::new(r"^(\d*)x(\d*)$").unwrap();
+        let Some(caps) = re.captures(spec) else {
+            return Self::load(spec);
+        };
+        match (caps[1].parse(), caps[2].parse()) {
+            (Ok(n), Ok(m)) if n > 0 && m > 0 => Self::grid(size, n, m),
+            _ => panic!("Bad grid {:?}, want positive columns and rows like 3x2", spec),
+        }
+    }
+
+    fn counts(&self, room: &Room) -> Vec<usize> {
+        self.zones
+            .iter()
+            .map(|z| room.robots.iter().filter(|b| z.contains(&b.position)).count())
+            .collect()
+    }
+
+    // Saturates rather than overflowing with lots of zones
+    fn safety_factor(&self, room: &Room) -> usize {
+        self.counts(room).iter().fold(1, |p, c| p.saturating_mul(*c))
+    }
+}
+
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 struct Room {
     size: Coord2,
@@ -75,28 +169,9 @@ impl Room {
         }
     }
 
-    fn count_by_quadrant(&self) -> Vec<usize> {
-        let mut counts = vec![0; 4];
-        let mx = self.size.x / 2;
-        let my = self.size.y / 2;
-        for bot in &self.robots {
-            let quadrant = match (bot.position.x.cmp(&mx), bot.position.y.cmp(&my)) {
-                (Ordering::Less, Ordering::Less) => Some(0),
-                (Ordering::Greater, Ordering::Less) => Some(1),
-                (Ordering::Less, Ordering::Greater) => Some(2),
-                (Ordering::Greater, Ordering::Greater) => Some(3),
-                _ => None,
-            };
-            if let Some(q) = quadrant {
-                *counts.get_mut(q).unwrap() += 1;
-            }
-        }
-        counts
-    }
-
     fn part1(&mut self) -> usize {
         self.move_n(100);
-        self.count_by_quadrant().iter().product()
+        Partition::quadrants(&self.size).safety_factor(self)
     }
 
     fn save_png(&self, fname: &str) {
@@ -118,18 +193,186 @@ impl Room {
         // Horiz: 79, 182, 285, ...
     }
 
-    fn part2(&mut self) {
-        for i in 0..999999 {
-            if i % 101 == 13 && i % 103 == 79 {
-                let fname = format!("img/{:0>5}.png", &i);
-                self.save_png(&fname);
-                return;
+    // Spread of the robots along one axis after t steps, as n^2 times the variance
+    fn spread(&self, t: i64, axis: fn(&Coord2) -> i64) -> i64 {
+        let size = axis(&self.size);
+        let (sum, sum_sq) = self
+            .robots
+            .iter()
+            .map(|b| (axis(&b.position) + axis(&b.velocity) * t).rem_euclid(size))
+            .fold((0, 0), |(s, sq), v| (s + v, sq + v * v));
+        self.robots.len() as i64 * sum_sq - sum * sum
+    }
+
+    // Each axis repeats with the room's size along it, so the picture's step along that axis is
+    // the one in the first period where the robots bunch up most
+    fn tightest(&self, axis: fn(&Coord2) -> i64) -> i64 {
+        (0..axis(&self.size))
+            .min_by_key(|t| self.spread(*t, axis))
+            .unwrap()
+    }
+
+    // The first step with the robots bunched up along both axes at once
+    fn part2(&self) -> Option<i64> {
+        let tx = self.tightest(|c| c.x);
+        let ty = self.tightest(|c| c.y);
+        crt(tx, self.size.x, ty, self.size.y).map(|(t, _)| t)
+    }
+
+    // After this many steps every robot is back where it started. Each robot repeats along an
+    // axis once its velocity has carried it a whole number of times across the room.
+    fn period(&self) -> i64 {
+        self.robots.iter().fold(1, |p, b| {
+            let px = self.size.x / gcd(b.velocity.x, self.size.x);
+            let py = self.size.y / gcd(b.velocity.y, self.size.y);
+            lcm(p, lcm(px, py))
+        })
+    }
+
+    // When robots i and j share a cell, as t = t0 mod m, if they ever do
+    fn meetings(&self, i: usize, j: usize) -> Option<(i64, i64)> {
+        let (a, b) = (&self.robots[i], &self.robots[j]);
+        let (tx, mx) = solve_linear(
+            a.velocity.x - b.velocity.x,
+            b.position.x - a.position.x,
+            self.size.x,
+        )?;
+        let (ty, my) = solve_linear(
+            a.velocity.y - b.velocity.y,
+            b.position.y - a.position.y,
+            self.size.y,
+        )?;
+        crt(tx, mx, ty, my)
+    }
+
+    // Steps in one period where some cell holds more than k robots, with the most in one cell.
+    // Any such cell has at least two robots meeting, so only the steps where some pair meets
+    // need looking at.
+    fn crowded(&self, k: usize) -> Vec<(i64, usize)> {
+        let period = self.period();
+        let mut meeting: HashMap<i64, Vec<usize>> = HashMap::new();
+        for (i, j) in (0..self.robots.len()).tuple_combinations() {
+            if let Some((t0, m)) = self.meetings(i, j) {
+                for t in (t0..period).step_by(m as usize) {
+                    meeting.entry(t).or_default().extend([i, j]);
+                }
             }
-            self.move_n(1);
         }
+        meeting
+            .into_iter()
+            .filter_map(|(t, bots)| {
+                let most = bots
+                    .into_iter()
+                    .unique()
+                    .map(|i| {
+                        let mut pos = self.robots[i].position;
+                        pos.add_n(&self.robots[i].velocity, &self.size, t);
+                        pos
+                    })
+                    .counts()
+                    .into_values()
+                    .max()
+                    .unwrap();
+                (most > k).then_some((t, most))
+            })
+            .sorted()
+            .collect()
+    }
+
+    // Robots in each zone for the first steps, and where the safety factor is lowest over a
+    // whole period. Robots bunched into a picture leave most zones nearly empty.
+    fn zone_report(&self, partition: &Partition, steps: i64) {
+        let mut room = self.clone();
+        let header = (0..partition.zones.len())
+            .map(|i| format!("{:>5}", format!("z{}", i)))
+            .join(" ");
+        println!(" step {}  safety", header);
+        for t in 0..=steps {
+            let counts = partition.counts(&room).iter().map(|c| format!("{:>5}", c)).join(" ");
+            println!("{:>5} {}  {}", t, counts, partition.safety_factor(&room));
+            room.move_n(1);
+        }
+
+        let mut room = self.clone();
+        let mut lowest = (usize::MAX, 0);
+        for t in 0..self.period() {
+            lowest = lowest.min((partition.safety_factor(&room), t));
+            room.move_n(1);
+        }
+        println!("Lowest safety factor {} at step {}", lowest.0, lowest.1);
+    }
+
+    fn analyse(&self, k: usize) {
+        assert!(k > 0, "Every step has a cell with a robot in");
+        let period = self.period();
+        println!("Room repeats every {} steps", lcm(self.size.x, self.size.y));
+        println!("Robots repeat every {} steps", period);
+        let crowded = self.crowded(k);
+        println!(
+            "{} steps per period with more than {} robots in a cell:",
+            crowded.len(),
+            k
+        );
+        for (t, most) in &crowded {
+            println!("{:>6}: {}", t, most);
+        }
+        // Robots only ever share cells at the steps crowded(1) finds
+        let shared = if k == 1 {
+            crowded.iter().map(|(t, _)| *t).collect_vec()
+        } else {
+            self.crowded(1).into_iter().map(|(t, _)| t).collect()
+        };
+        match (0..period).find(|t| shared.binary_search(t).is_err()) {
+            Some(t) => println!("All robots apart first at step {}", t),
+            None => println!("Robots are never all apart"),
+        }
+    }
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a.abs()
+    } else {
+        gcd(b, a % b)
     }
 }
 
+fn lcm(a: i64, b: i64) -> i64 {
+    a / gcd(a, b) * b
+}
+
+// All t with a * t = b mod m, as t = t0 mod m', or None if there aren't any
+fn solve_linear(a: i64, b: i64, m: i64) -> Option<(i64, i64)> {
+    let (g, x, _) = extended_gcd(a.rem_euclid(m), m);
+    if b.rem_euclid(g) != 0 {
+        return None;
+    }
+    let m = m / g;
+    Some(((b / g).rem_euclid(m) * x.rem_euclid(m) % m, m))
+}
+
+// Returns (g, x, y) with a * x + b * y == g == gcd(a, b)
+fn extended_gcd(a: i64, b: i64) -> (i64, i64, i64) {
+    if b == 0 {
+        (a, 1, 0)
+    } else {
+        let (g, x, y) = extended_gcd(b, a % b);
+        (g, y, x - (a / b) * y)
+    }
+}
+
+// The smallest t >= 0 with t = a1 mod m1 and t = a2 mod m2, and the modulus it repeats with.
+// The moduli needn't be coprime, but then there may be no such t.
+fn crt(a1: i64, m1: i64, a2: i64, m2: i64) -> Option<(i64, i64)> {
+    let (g, x, _) = extended_gcd(m1, m2);
+    if (a2 - a1) % g != 0 {
+        return None;
+    }
+    let lcm = m1 / g * m2;
+    let k = ((a2 - a1) / g * x).rem_euclid(m2 / g);
+    Some(((a1 + m1 * k).rem_euclid(lcm), lcm))
+}
+
 impl Display for Room {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let by_pos = self.count_by_pos();
@@ -151,6 +394,26 @@ impl Display for Room {
 fn main() {
     let fname = args().nth(1).unwrap();
     let mut room = Room::parse(&fname);
+    if args().nth(2).as_deref() == Some("analyse") {
+        let k = args().nth(3).map_or(1, |k| k.parse().unwrap());
+        room.analyse(k);
+        return;
+    }
+    if args().nth(2).as_deref() == Some("zones") {
+        let partition = Partition::from_spec(&args().nth(3).unwrap(), &room.size);
+        let steps = args().nth(4).map_or(100, |n| n.parse().unwrap());
+        room.zone_report(&partition, steps);
+        return;
+    }
     println!("Part 1: {}", room.clone().part1());
-    room.part2();
+    match room.part2() {
+        Some(t) => {
+            println!("Part 2: {}", t);
+            if let Some(out) = args().nth(2) {
+                room.move_n(t);
+                room.save_png(&out);
+            }
+        }
+        None => println!("Part 2: no step lines up on both axes"),
+    }
 }
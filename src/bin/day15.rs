@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env::args;
 use std::fmt::Display;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::process::Command;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct Dir {
@@ -25,8 +27,14 @@ impl Dir {
         }
     }
 
-    fn is_vertical(&self) -> bool {
-        self.dx == 0
+    fn symbol(&self) -> char {
+        match *self {
+            Self::LEFT => '<',
+            Self::RIGHT => '>',
+            Self::UP => '^',
+            Self::DOWN => 'v',
+            _ => unreachable!(),
+        }
     }
 }
 
@@ -63,28 +71,62 @@ impl Pos {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Tile {
-    Empty,
-    Bot,
-    Box,
-    BoxLeft,
-    BoxRight,
-    Wall,
+// How many cells each map character becomes
+#[derive(Debug, Clone, Copy)]
+struct Scale {
+    wide: i64,
+    tall: i64,
 }
 
-impl Tile {
-    fn parse(c: char, wide: i64) -> Vec<Self> {
-        match (c, wide) {
-            ('.', 1) => vec![Self::Empty],
-            ('@', 1) => vec![Self::Bot],
-            ('O', 1) => vec![Self::Box],
-            ('#', 1) => vec![Self::Wall],
-            ('.', 2) => vec![Self::Empty, Self::Empty],
-            ('@', 2) => vec![Self::Bot, Self::Empty],
-            ('O', 2) => vec![Self::BoxLeft, Self::BoxRight],
-            ('#', 2) => vec![Self::Wall, Self::Wall],
-            _ => panic!("Invalid tile: {}", c),
+impl Scale {
+    // From args like "wide=3 tall=2"
+    fn parse<'a>(specs: impl Iterator<Item = &'a str>) -> Self {
+        let mut scale = Self { wide: 1, tall: 1 };
+        for spec in specs {
+            let (key, val) = spec.split_once('=').unwrap();
+            match key {
+                "wide" => scale.wide = val.parse().unwrap(),
+                "tall" => scale.tall = val.parse().unwrap(),
+                _ => panic!("Unknown setting {}", key),
+            }
+        }
+        assert!(scale.wide > 0 && scale.tall > 0, "Scale must be positive");
+        scale
+    }
+
+    fn wide(wide: i64) -> Self {
+        Self { wide, tall: 1 }
+    }
+}
+
+// A box and every cell it covers
+#[derive(Debug, Clone)]
+struct Piece {
+    label: char,
+    cells: Vec<Pos>,
+}
+
+impl Piece {
+    // Its closest edges to the top and left of the map
+    fn corner(&self) -> Pos {
+        let x = self.cells.iter().map(|p| p.x).min().unwrap();
+        let y = self.cells.iter().map(|p| p.y).min().unwrap();
+        Pos::new(x, y)
+    }
+
+    fn glyph(&self, pos: &Pos) -> char {
+        let corner = self.corner();
+        if self.cells.len() == 1 {
+            'O'
+        } else if self.cells.iter().all(|p| p.y == corner.y) {
+            // Single row boxes show their ends, like [] or [=]
+            match pos.x {
+                x if x == corner.x => '[',
+                x if x == corner.x + self.cells.len() as i64 - 1 => ']',
+                _ => '=',
+            }
+        } else {
+            self.label
         }
     }
 }
@@ -94,92 +136,123 @@ struct Warehouse {
     width: i64,
     height: i64,
     bot: Pos,
-    obstacles: HashMap<Pos, Tile>,
+    walls: HashSet<Pos>,
+    pieces: Vec<Piece>,
+    // Which piece is in each cell
+    occupied: HashMap<Pos, usize>,
     moves: Vec<Dir>,
 }
 
 impl Warehouse {
-    fn parse(fname: &str, wide: i64) -> Self {
-        let mut width = 0;
-        let mut height = 0;
-        let mut obstacles = HashMap::new();
+    // 'O' is a box, and any other letter joins up with the same letter next to it into one
+    // piece, so "AA" over "A." is an L. Every character then grows to the scale.
+    fn parse(fname: &str, scale: Scale) -> Self {
+        let contents = fs::read_to_string(fname).unwrap();
+        let mut lines = contents.lines();
+        let map: Vec<Vec<char>> = lines
+            .by_ref()
+            .take_while(|l| !l.is_empty())
+            .map(|l| l.chars().collect())
+            .collect();
+        let moves = lines.flat_map(|l| l.chars().map(Dir::parse)).collect();
+
         let mut bot = Pos::new(0, 0);
-        let mut moves = Vec::new();
-        let mut done_map = false;
+        let mut walls = HashSet::new();
+        let mut pieces: Vec<Piece> = Vec::new();
+        let mut piece_at: HashMap<Pos, usize> = HashMap::new();
+        let expand = |p: Pos| -> Vec<Pos> {
+            (0..scale.tall)
+                .flat_map(|dy| (0..scale.wide).map(move |dx| (dx, dy)))
+                .map(|(dx, dy)| Pos::new(p.x * scale.wide + dx, p.y * scale.tall + dy))
+                .collect()
+        };
+        let at = |p: &Pos| {
+            map.get(p.y as usize)
+                .and_then(|row| row.get(p.x as usize))
+                .copied()
+        };
 
-        let contents = fs::read_to_string(fname).unwrap();
-        for (y, line) in contents.lines().enumerate() {
-            if done_map {
-                moves.extend(line.chars().map(|c| Dir::parse(c)));
-            } else if line.is_empty() {
-                done_map = true;
-            } else {
-                height = (y + 1) as i64;
-                let mut x = 0;
-                for c in line.chars() {
-                    let tiles = Tile::parse(c, wide);
-                    for t in tiles {
-                        match t {
-                            Tile::Bot => bot = Pos::new(x as i64, y as i64),
-                            Tile::Empty => (),
-                            _ => {
-                                obstacles.insert(Pos::new(x as i64, y as i64), t);
+        for (y, row) in map.iter().enumerate() {
+            for (x, c) in row.iter().enumerate() {
+                let p = Pos::new(x as i64, y as i64);
+                match c {
+                    '.' => (),
+                    '#' => walls.extend(expand(p)),
+                    '@' => bot = Pos::new(p.x * scale.wide, p.y * scale.tall),
+                    'O' => pieces.push(Piece {
+                        label: 'O',
+                        cells: expand(p),
+                    }),
+                    c if c.is_ascii_alphabetic() => {
+                        if piece_at.contains_key(&p) {
+                            continue;
+                        }
+                        let id = pieces.len();
+                        let mut cells = Vec::new();
+                        let mut todo = vec![p];
+                        piece_at.insert(p, id);
+                        while let Some(q) = todo.pop() {
+                            cells.extend(expand(q));
+                            for dir in [Dir::LEFT, Dir::RIGHT, Dir::UP, Dir::DOWN] {
+                                let n = q.go(&dir);
+                                if at(&n) == Some(*c) && !piece_at.contains_key(&n) {
+                                    piece_at.insert(n, id);
+                                    todo.push(n);
+                                }
                             }
                         }
-                        x += 1;
-                        width = x;
+                        pieces.push(Piece { label: *c, cells });
                     }
+                    _ => panic!("Invalid tile: {}", c),
                 }
             }
         }
+
+        let occupied = pieces
+            .iter()
+            .enumerate()
+            .flat_map(|(id, piece)| piece.cells.iter().map(move |p| (*p, id)))
+            .collect();
         Self {
-            width,
-            height,
+            width: map.iter().map(|r| r.len()).max().unwrap_or(0) as i64 * scale.wide,
+            height: map.len() as i64 * scale.tall,
             bot,
-            obstacles,
+            walls,
+            pieces,
+            occupied,
             moves,
         }
     }
 
-    fn need_move(&self, pos: &Pos, dir: &Dir, t: Tile) -> Vec<Pos> {
-        match (t, dir.is_vertical()) {
-            (Tile::Box, _) | (_, false) => vec![*pos],
-            (Tile::BoxLeft, true) => vec![*pos, pos.go(&Dir::RIGHT)],
-            (Tile::BoxRight, true) => vec![*pos, pos.go(&Dir::LEFT)],
-            _ => unreachable!(),
-        }
-    }
-
+    // Everything in the way gets pushed, and everything in the way of any cell of that, and
+    // so on. One wall anywhere stops the lot.
     fn move_one(&mut self, dir: &Dir) {
-        let mut to_move = Vec::new();
-        let mut check = Vec::new();
         let bot_dest = self.bot.go(dir);
-        check.push(bot_dest);
-
-        loop {
-            let mut new_check = Vec::new();
-            for pos in check.iter() {
-                if let Some(t) = self.obstacles.get(pos) {
-                    if t == &Tile::Wall {
-                        return;
-                    }
-                    for np in self.need_move(pos, dir, *t) {
-                        to_move.push((np, *self.obstacles.get(&np).unwrap()));
-                        new_check.push(np.go(dir));
-                    }
-                }
+        let mut pushed = Vec::new();
+        let mut seen = HashSet::new();
+        let mut check = vec![bot_dest];
+        while let Some(pos) = check.pop() {
+            if self.walls.contains(&pos) {
+                return;
             }
-            if new_check.is_empty() {
-                break;
+            if let Some(&id) = self.occupied.get(&pos) {
+                if seen.insert(id) {
+                    pushed.push(id);
+                    check.extend(self.pieces[id].cells.iter().map(|p| p.go(dir)));
+                }
             }
-            check = new_check;
         }
 
-        for (pos, _) in to_move.iter() {
-            self.obstacles.remove(&pos);
+        for id in &pushed {
+            for pos in &self.pieces[*id].cells {
+                self.occupied.remove(pos);
+            }
         }
-        for (pos, t) in to_move.iter() {
-            self.obstacles.insert(pos.go(dir), *t);
+        for id in pushed {
+            for pos in self.pieces[id].cells.iter_mut() {
+                *pos = pos.go(dir);
+                self.occupied.insert(*pos, id);
+            }
         }
         self.bot = bot_dest;
     }
@@ -187,16 +260,16 @@ impl Warehouse {
     fn move_all(&mut self) {
         for dir in self.moves.clone().iter() {
             // println!("{}", dir);
-            self.move_one(&dir);
+            self.move_one(dir);
             // print!("{}", self);
         }
     }
 
     fn score(&self) -> i64 {
-        self.obstacles
+        self.pieces
             .iter()
-            .filter(|(_, &t)| t == Tile::Box || t == Tile::BoxLeft)
-            .map(|(p, _)| p.x + 100 * p.y)
+            .map(|piece| piece.corner())
+            .map(|p| p.x + 100 * p.y)
             .sum()
     }
 
@@ -211,19 +284,16 @@ impl Display for Warehouse {
         for y in 0..self.height {
             for x in 0..self.width {
                 let pos = Pos::new(x, y);
-                if pos == self.bot {
-                    write!(f, "{}", '@')?;
+                let c = if pos == self.bot {
+                    '@'
+                } else if self.walls.contains(&pos) {
+                    '#'
+                } else if let Some(id) = self.occupied.get(&pos) {
+                    self.pieces[*id].glyph(&pos)
                 } else {
-                    let c = match self.obstacles.get(&pos) {
-                        None => '.',
-                        Some(Tile::Box) => 'O',
-                        Some(Tile::BoxLeft) => '[',
-                        Some(Tile::BoxRight) => ']',
-                        Some(Tile::Wall) => '#',
-                        _ => unreachable!(),
-                    };
-                    write!(f, "{}", c)?;
-                }
+                    '.'
+                };
+                write!(f, "{}", c)?;
             }
             writeln!(f)?;
         }
@@ -231,12 +301,165 @@ impl Display for Warehouse {
     }
 }
 
+// Puts the terminal into unbuffered, silent input for as long as it's alive
+struct RawTerminal {
+    saved: String,
+}
+
+impl RawTerminal {
+    fn stty(args: &[&str]) -> String {
+        let tty = File::open("/dev/tty").unwrap();
+        let out = Command::new("stty").args(args).stdin(tty).output().unwrap();
+        String::from_utf8(out.stdout).unwrap().trim().to_owned()
+    }
+
+    fn new() -> Self {
+        let saved = Self::stty(&["-g"]);
+        Self::stty(&["-icanon", "-echo", "-isig", "min", "1"]);
+        Self { saved }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        Self::stty(&[&self.saved]);
+    }
+}
+
+enum Key {
+    Move(Dir),
+    Undo,
+    Redo,
+    Save,
+    Quit,
+    Other,
+}
+
+// Keys from the terminal. The bytes of one key press come in the same read, so an ESC at the
+// end of what was read is the escape key itself rather than the start of an arrow key.
+struct Keys<R: Read> {
+    input: R,
+    pending: VecDeque<u8>,
+}
+
+impl<R: Read> Keys<R> {
+    fn new(input: R) -> Self {
+        Self {
+            input,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn next_key(&mut self) -> Key {
+        if self.pending.is_empty() {
+            let mut buf = [0; 64];
+            match self.input.read(&mut buf) {
+                Ok(n) if n > 0 => self.pending.extend(&buf[..n]),
+                _ => return Key::Quit,
+            }
+        }
+        match self.pending.pop_front().unwrap() {
+            // Ctrl-C as well, since signals are off so the terminal always gets restored
+            b'q' | 0x03 => Key::Quit,
+            b'u' => Key::Undo,
+            b'r' => Key::Redo,
+            b's' => Key::Save,
+            // Arrow keys send ESC [ A to D
+            0x1b if self.pending.front() == Some(&b'[') => {
+                self.pending.pop_front();
+                match self.pending.pop_front() {
+                    Some(b'A') => Key::Move(Dir::UP),
+                    Some(b'B') => Key::Move(Dir::DOWN),
+                    Some(b'C') => Key::Move(Dir::RIGHT),
+                    Some(b'D') => Key::Move(Dir::LEFT),
+                    _ => Key::Other,
+                }
+            }
+            _ => Key::Other,
+        }
+    }
+}
+
+// The map part of an input file, then the moves in lines as long as the puzzle's
+fn save_moves(fname: &str, out: &str, moves: &[Dir]) {
+    let contents = fs::read_to_string(fname).unwrap();
+    let mut text = String::new();
+    for line in contents.lines().take_while(|l| !l.is_empty()) {
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.push('\n');
+    for chunk in moves.chunks(1000) {
+        text.extend(chunk.iter().map(|d| d.symbol()));
+        text.push('\n');
+    }
+    fs::write(out, text).unwrap();
+}
+
+// Drive the robot with the arrow keys. Every state is kept, so undo and redo just move
+// through them, and a new move after an undo drops the ones that were undone.
+fn play(fname: &str, scale: Scale, out: &str) {
+    let start = Warehouse::parse(fname, scale);
+    let mut history = vec![start];
+    let mut typed: Vec<Dir> = Vec::new();
+    let mut at = 0;
+    let mut status = String::new();
+
+    let _term = RawTerminal::new();
+    let mut keys = Keys::new(io::stdin().lock());
+    loop {
+        let current = &history[at];
+        print!("\x1b[2J\x1b[H{}", current);
+        println!("GPS: {}  moves: {}/{}", current.score(), at, typed.len());
+        println!("arrows move, u undo, r redo, s save to {}, q quit", out);
+        println!("{}", status);
+        io::stdout().flush().unwrap();
+        status.clear();
+
+        match keys.next_key() {
+            Key::Move(dir) => {
+                let mut next = history[at].clone();
+                next.move_one(&dir);
+                history.truncate(at + 1);
+                typed.truncate(at);
+                history.push(next);
+                typed.push(dir);
+                at += 1;
+            }
+            Key::Undo if at > 0 => at -= 1,
+            Key::Redo if at < typed.len() => at += 1,
+            Key::Save => {
+                save_moves(fname, out, &typed[..at]);
+                status = format!("Saved {} moves", at);
+            }
+            Key::Quit => break,
+            _ => {}
+        }
+    }
+}
+
 fn main() {
     let fname = args().nth(1).unwrap();
 
-    let mut warehouse = Warehouse::parse(&fname, 1);
-    println!("{}", warehouse.result());
+    // Anything with an '=' is a scale setting, the rest say what to do
+    let rest: Vec<String> = args().skip(2).collect();
+    let (specs, mode): (Vec<&String>, Vec<&String>) = rest.iter().partition(|a| a.contains('='));
+    let scale = (!specs.is_empty()).then(|| Scale::parse(specs.into_iter().map(|s| s.as_str())));
 
-    let mut warehouse = Warehouse::parse(&fname, 2);
-    println!("{}", warehouse.result());
+    match mode.first().map(|s| s.as_str()) {
+        Some("play") => {
+            let out = mode.get(1).map_or("moves.txt", |s| s.as_str());
+            play(&fname, scale.unwrap_or(Scale::wide(1)), out);
+        }
+        _ => match scale {
+            Some(scale) => println!("{}", Warehouse::parse(&fname, scale).result()),
+            None => {
+                let mut warehouse = Warehouse::parse(&fname, Scale::wide(1));
+                println!("{}", warehouse.result());
+
+                let mut warehouse = Warehouse::parse(&fname, Scale::wide(2));
+                println!("{}", warehouse.result());
+            }
+        },
+    }
 }